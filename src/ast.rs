@@ -1,28 +1,120 @@
+use crate::lex::Token;
 use std::fmt;
 
 pub enum Statement {
     Expression(ExpressionStmt),
-    VarDeclaration(String, Option<ExpressionStmt>),
-    For,
-    If,
+    VarDeclaration(Identifier, Option<ExpressionStmt>),
+    Function(FunctionDecl),
+    Class(ClassDecl),
+    For(ForStmt),
+    If(IfStmt),
     Print(PrintStmt),
-    Return,
-    While,
-    Block,
+    Return(ReturnStmt),
+    While(WhileStmt),
+    Block(Vec<Statement>),
 }
 
 pub enum ExpressionStmt {
-    Number(f64),
-    Identifier(String),
-    Unary(String, Box<ExpressionStmt>),
-    Binary(String, Box<(ExpressionStmt, ExpressionStmt)>),
+    Number(f64, Position),
+    String(String, Position),
+    Bool(bool, Position),
+    Nil(Position),
+    Identifier(Identifier),
+    Assign(Identifier, Box<ExpressionStmt>),
+    Call(Box<ExpressionStmt>, Vec<ExpressionStmt>, Operator),
+    Get(Box<ExpressionStmt>, Identifier),
+    Set(Box<ExpressionStmt>, Identifier, Box<ExpressionStmt>),
+    This(Identifier),
+    Super(Identifier, Identifier),
+    Unary(Operator, Box<ExpressionStmt>),
+    Binary(Operator, Box<(ExpressionStmt, ExpressionStmt)>),
+}
+
+/// Where a token without a name of its own, such as a literal, was written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl From<&Token<'_>> for Position {
+    fn from(token: &Token<'_>) -> Self {
+        Self {
+            line: token.line,
+            column: token.column,
+        }
+    }
+}
+
+/// A variable name together with the position it was written at.
+pub struct Identifier {
+    pub name: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl From<&Token<'_>> for Identifier {
+    fn from(token: &Token<'_>) -> Self {
+        Self {
+            name: token.lexeme.into(),
+            line: token.line,
+            column: token.column,
+        }
+    }
+}
+
+impl fmt::Display for Identifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// An operator, or the parenthesis of a call, with its position so runtime
+/// errors can point at it.
+pub struct Operator {
+    pub symbol: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl From<&Token<'_>> for Operator {
+    fn from(token: &Token<'_>) -> Self {
+        Self {
+            symbol: token.lexeme.into(),
+            line: token.line,
+            column: token.column,
+        }
+    }
+}
+
+impl fmt::Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.symbol)
+    }
 }
 
 impl fmt::Display for ExpressionStmt {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            ExpressionStmt::Number(x) => write!(f, "{}", x),
+            ExpressionStmt::Number(x, _) => write!(f, "{}", x),
+            ExpressionStmt::String(s, _) => write!(f, "\"{}\"", s),
+            ExpressionStmt::Bool(b, _) => write!(f, "{}", b),
+            ExpressionStmt::Nil(_) => write!(f, "nil"),
             ExpressionStmt::Identifier(id) => write!(f, "{}", id),
+            ExpressionStmt::Assign(id, value) => write!(f, "(= {} {})", id, value),
+            ExpressionStmt::Call(callee, arguments, _) => {
+                write!(f, "(call {}", callee)?;
+                for argument in arguments {
+                    write!(f, " {}", argument)?;
+                }
+                write!(f, ")")
+            }
+            ExpressionStmt::Get(object, name) => write!(f, "(. {} {})", object, name),
+            ExpressionStmt::Set(object, name, value) => {
+                write!(f, "(= (. {} {}) {})", object, name, value)
+            }
+            ExpressionStmt::This(_) => write!(f, "this"),
+            ExpressionStmt::Super(_, method) => write!(f, "(. super {})", method),
             ExpressionStmt::Unary(token, operand) => {
                 write!(f, "({} {})", token, operand)
             }
@@ -42,3 +134,39 @@ impl fmt::Display for PrintStmt {
         write!(f, "(print {})", self.expr)
     }
 }
+
+pub struct IfStmt {
+    pub condition: ExpressionStmt,
+    pub then_branch: Box<Statement>,
+    pub else_branch: Option<Box<Statement>>,
+}
+
+pub struct WhileStmt {
+    pub condition: ExpressionStmt,
+    pub body: Box<Statement>,
+}
+
+pub struct ForStmt {
+    pub initializer: Option<Box<Statement>>,
+    pub condition: Option<ExpressionStmt>,
+    pub increment: Option<ExpressionStmt>,
+    pub body: Box<Statement>,
+}
+
+pub struct FunctionDecl {
+    pub name: Identifier,
+    pub params: Vec<Identifier>,
+    pub body: Vec<Statement>,
+}
+
+pub struct ClassDecl {
+    pub name: Identifier,
+    pub superclass: Option<Identifier>,
+    pub methods: Vec<FunctionDecl>,
+}
+
+pub struct ReturnStmt {
+    pub value: Option<ExpressionStmt>,
+    pub line: usize,
+    pub column: usize,
+}
@@ -0,0 +1,362 @@
+use crate::Value;
+use crate::error::Error;
+use crate::gc::Heap;
+use crate::object::Function;
+use crate::verify::verify;
+use crate::vm::{Chunk, LineRun};
+
+/// The first bytes of every `.loxc` file.
+pub const MAGIC: &[u8; 4] = b"LOXC";
+/// Bumped whenever the layout of `.loxc` files or the meaning of an opcode changes.
+pub const VERSION: u16 = 1;
+
+/// Functions nested deeper than this are rejected rather than risking a
+/// stack overflow while loading.
+const MAX_NESTING: usize = 256;
+
+const TAG_NIL: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_NUMBER: u8 = 2;
+const TAG_STRING: u8 = 3;
+const TAG_FUNCTION: u8 = 4;
+
+/// Writes a compiled script and every function nested in it as a `.loxc` file.
+///
+/// The file is `MAGIC`, `VERSION` as a little-endian `u16`, the script, and a
+/// CRC-32 of everything before it. A function is its optional name, arity,
+/// upvalue count, chunk name, code, constants and line runs. Integers are
+/// little-endian `u32`s, strings and lists are prefixed by their length, and
+/// each constant by a tag byte.
+pub fn serialize(script: &Function) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.extend(VERSION.to_le_bytes());
+    write_function(&mut out, script);
+    let checksum = crc32(&out);
+    out.extend(checksum.to_le_bytes());
+    out
+}
+
+/// Reads a script written by `serialize`, allocating its strings and
+/// functions on `heap`. The bytecode is verified, so it is safe to run even
+/// if the file did not come from `serialize`.
+pub(crate) fn deserialize(bytes: &[u8], heap: &Heap) -> Result<Function, Error> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(Error::not_bytecode());
+    }
+    let mut reader = Reader {
+        bytes,
+        offset: MAGIC.len(),
+        heap,
+    };
+    // Check the version first, so that files from other releases say so
+    // instead of failing the checksum or parsing.
+    let version = u16::from_le_bytes(reader.array()?);
+    if version != VERSION {
+        return Err(Error::bytecode_version_mismatch(version, VERSION));
+    }
+
+    let Some(payload_len) = bytes
+        .len()
+        .checked_sub(4)
+        .filter(|&len| len >= reader.offset)
+    else {
+        return Err(Error::corrupt_bytecode(bytes.len(), "missing checksum"));
+    };
+    let (payload, checksum) = bytes.split_at(payload_len);
+    let expected = u32::from_le_bytes(checksum.try_into().unwrap());
+    let found = crc32(payload);
+    if found != expected {
+        return Err(Error::checksum_mismatch(expected, found));
+    }
+
+    reader.bytes = payload;
+    let script = reader.function(0)?;
+    if reader.offset != payload.len() {
+        return Err(Error::corrupt_bytecode(
+            reader.offset,
+            "unexpected bytes after the script",
+        ));
+    }
+    verify(&script)?;
+    Ok(script)
+}
+
+fn write_usize(out: &mut Vec<u8>, n: usize) {
+    let n = u32::try_from(n).expect("compiled chunks fit in u32 sizes");
+    out.extend(n.to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_usize(out, s.len());
+    out.extend(s.as_bytes());
+}
+
+fn write_function(out: &mut Vec<u8>, function: &Function) {
+    match &function.name {
+        Some(name) => {
+            out.push(1);
+            write_str(out, name);
+        }
+        None => out.push(0),
+    }
+    write_usize(out, function.arity);
+    write_usize(out, function.upvalue_count);
+
+    let chunk = &function.chunk;
+    write_str(out, &chunk.name);
+    write_usize(out, chunk.code.len());
+    out.extend(&chunk.code);
+
+    write_usize(out, chunk.constants.len());
+    for constant in &chunk.constants {
+        match constant {
+            Value::Nil => out.push(TAG_NIL),
+            Value::Bool(b) => out.extend([TAG_BOOL, *b as u8]),
+            Value::Number(x) => {
+                out.push(TAG_NUMBER);
+                out.extend(x.to_bits().to_le_bytes());
+            }
+            Value::String(s) => {
+                out.push(TAG_STRING);
+                write_str(out, s);
+            }
+            Value::Function(function) => {
+                out.push(TAG_FUNCTION);
+                write_function(out, function);
+            }
+            constant => panic!(
+                "the compiler never makes a {} constant",
+                constant.type_name()
+            ),
+        }
+    }
+
+    write_usize(out, chunk.lines.len());
+    for run in &chunk.lines {
+        write_usize(out, run.line);
+        write_usize(out, run.column);
+        write_usize(out, run.count);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    heap: &'a Heap,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let bytes = self
+            .offset
+            .checked_add(len)
+            .and_then(|end| self.bytes.get(self.offset..end))
+            .ok_or_else(|| Error::corrupt_bytecode(self.offset, "unexpected end of file"))?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn usize(&mut self) -> Result<usize, Error> {
+        Ok(u32::from_le_bytes(self.array()?) as usize)
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let len = self.usize()?;
+        let start = self.offset;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| Error::corrupt_bytecode(start, "string is not valid UTF-8"))
+    }
+
+    fn function(&mut self, depth: usize) -> Result<Function, Error> {
+        if depth > MAX_NESTING {
+            return Err(Error::corrupt_bytecode(
+                self.offset,
+                "functions are nested too deeply",
+            ));
+        }
+        let name = match self.u8()? {
+            0 => None,
+            1 => Some(self.string()?),
+            flag => {
+                return Err(Error::corrupt_bytecode(
+                    self.offset - 1,
+                    format!("invalid name flag {flag}"),
+                ));
+            }
+        };
+        let arity = self.usize()?;
+        let upvalue_count = self.usize()?;
+
+        let mut chunk = Chunk::new(&self.string()?);
+        let code_len = self.usize()?;
+        chunk.code = self.take(code_len)?.to_vec();
+
+        // Counts are not trusted for preallocation, a corrupt one could be huge.
+        for _ in 0..self.usize()? {
+            let tag_offset = self.offset;
+            let constant = match self.u8()? {
+                TAG_NIL => Value::Nil,
+                TAG_BOOL => Value::Bool(self.u8()? != 0),
+                TAG_NUMBER => Value::Number(f64::from_bits(u64::from_le_bytes(self.array()?))),
+                TAG_STRING => Value::String(self.heap.intern(self.string()?)),
+                TAG_FUNCTION => Value::Function(self.heap.alloc(self.function(depth + 1)?)),
+                tag => {
+                    return Err(Error::corrupt_bytecode(
+                        tag_offset,
+                        format!("invalid constant tag {tag}"),
+                    ));
+                }
+            };
+            chunk.constants.push(constant);
+        }
+
+        let mut covered = 0usize;
+        for _ in 0..self.usize()? {
+            let run = LineRun {
+                line: self.usize()?,
+                column: self.usize()?,
+                count: self.usize()?,
+            };
+            covered = covered.saturating_add(run.count);
+            chunk.lines.push(run);
+        }
+        if covered != chunk.code.len() {
+            return Err(Error::corrupt_bytecode(
+                self.offset,
+                format!(
+                    "line table covers {covered} bytes but the code has {}",
+                    chunk.code.len()
+                ),
+            ));
+        }
+
+        Ok(Function {
+            name,
+            arity,
+            upvalue_count,
+            chunk,
+        })
+    }
+}
+
+/// The CRC-32 used by zip and PNG.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[test]
+fn tests() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+    let input = "
+        fun outer(a, b) {
+          var s = \"caf\u{e9}\";
+          fun inner() { return a + b + -0; }
+          return inner;
+        }
+        class A < B {
+          m() { return super.m(); }
+        }
+        var i = 0;
+        while (i < 2) i = i + 1.5;
+    ";
+    let mut program = crate::Parser::new(input).statements().unwrap();
+    let heap = Heap::new();
+    let script = crate::compiler::compile(&mut program, &heap).unwrap();
+    let bytes = serialize(&script);
+    assert!(bytes.starts_with(b"LOXC\x01\x00"));
+
+    {
+        // Loading into a fresh heap gives back the same chunks.
+        let heap = Heap::new();
+        let loaded = deserialize(&bytes, &heap).unwrap();
+        assert_eq!(
+            crate::disasm::render(&loaded),
+            crate::disasm::render(&script)
+        );
+        let functions = crate::disasm::functions(&loaded);
+        assert_eq!(
+            (functions[1].arity, functions[1].name.as_deref()),
+            (2, Some("outer"))
+        );
+        assert_eq!(functions[2].name.as_deref(), Some("inner"));
+        assert_eq!(functions[2].upvalue_count, 2);
+        // Strings are interned, so the VM can compare them by identity.
+        assert!(heap.find_string("café").is_some());
+    }
+    {
+        let heap = Heap::new();
+        assert!(matches!(
+            deserialize(b"print 1;", &heap),
+            Err(Error::NotBytecode)
+        ));
+
+        let mut newer = bytes.clone();
+        newer[4] = 2;
+        assert!(matches!(
+            deserialize(&newer, &heap),
+            Err(Error::BytecodeVersionMismatch {
+                found: 2,
+                expected: 1
+            })
+        ));
+
+        let mut flipped = bytes.clone();
+        flipped[20] ^= 0x40;
+        assert!(matches!(
+            deserialize(&flipped, &heap),
+            Err(Error::ChecksumMismatch { .. })
+        ));
+
+        // A truncated file with a valid checksum still fails cleanly.
+        let with_checksum = |payload: &[u8]| {
+            let mut bytes = payload.to_vec();
+            bytes.extend(crc32(payload).to_le_bytes());
+            bytes
+        };
+        let payload = &bytes[..bytes.len() - 4];
+        for len in [6, 7, 12, payload.len() / 2, payload.len() - 1] {
+            let error = deserialize(&with_checksum(&payload[..len]), &heap).unwrap_err();
+            assert!(
+                matches!(&error, Error::CorruptBytecode { offset, .. } if *offset <= len),
+                "{error}"
+            );
+        }
+        let mut longer = payload.to_vec();
+        longer.push(0);
+        assert!(matches!(
+            deserialize(&with_checksum(&longer), &heap),
+            Err(Error::CorruptBytecode { .. })
+        ));
+        assert!(matches!(
+            deserialize(b"LOXC\x01\x00\x00", &heap),
+            Err(Error::CorruptBytecode { offset: 7, .. })
+        ));
+
+        // Well-formed files must still hold bytecode that is safe to run.
+        let mut program = crate::Parser::new("print 1;").statements().unwrap();
+        let mut script = crate::compiler::compile(&mut program, &heap).unwrap();
+        script.chunk.code[1] = 9;
+        assert!(matches!(
+            deserialize(&serialize(&script), &heap),
+            Err(Error::InvalidBytecode { offset: 0, .. })
+        ));
+    }
+}
@@ -1,98 +1,1316 @@
-use crate::{ast, vm};
+use crate::{Value, ast, error::Error, fold, gc::Heap, object::Function, vm};
 
-pub fn compile(statement: &ast::Statement) -> vm::Chunk {
-    let mut chunk = vm::Chunk::new("main");
+type CompileResult<T> = Result<T, Error>;
 
-    match statement {
-        ast::Statement::Expression(expr) => compile_expression(&mut chunk, expr),
-        ast::Statement::Print(print_stmt) => compile_print_expression(&mut chunk, print_stmt),
-        _ => todo!(),
-    }
+#[derive(PartialEq, Clone, Copy)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
 
-    chunk
+struct Local {
+    name: String,
+    // Where the variable was declared.
+    position: ast::Position,
+    // `None` while the initializer of the variable is being compiled.
+    depth: Option<usize>,
+    // Set when a closure captures the local, so it is hoisted when it leaves scope.
+    is_captured: bool,
 }
 
-pub fn compile_expression(chunk: &mut vm::Chunk, expr: &ast::ExpressionStmt) {
-    match expr {
-        ast::ExpressionStmt::Number(x) => compile_number(chunk, *x),
-        ast::ExpressionStmt::Identifier(_) => todo!(),
-        ast::ExpressionStmt::Unary(op, expr) => compile_unary(chunk, op, expr),
-        ast::ExpressionStmt::Binary(op, expr_pair) => compile_binary(chunk, op, expr_pair),
-    }
+/// A variable captured by the function, either a local slot of the enclosing
+/// function or one of the enclosing function's own upvalues.
+struct Upvalue {
+    index: u8,
+    is_local: bool,
 }
 
-fn compile_number(chunk: &mut vm::Chunk, x: f64) {
-    let id = chunk.write_constant(x);
-    chunk.emit(vm::OpCode::Constant);
-    chunk.emit(id as u8);
+/// The class whose body is being compiled.
+struct ClassState {
+    has_superclass: bool,
 }
 
-fn compile_unary(chunk: &mut vm::Chunk, op: &str, expr: &ast::ExpressionStmt) {
-    compile_expression(chunk, expr);
-    // TODO: refactor
-    if op.starts_with("+") {
-        chunk.emit(vm::OpCode::OpAdd);
-    } else if op.starts_with("-") {
-        chunk.emit(vm::OpCode::OpNegate);
-    } else {
-        panic!("opcode error");
-    }
+/// Compiles a single function. Nested function declarations swap in a fresh
+/// compiler and keep the outer one in `enclosing` until the body is done.
+struct Compiler<'h> {
+    heap: &'h Heap,
+    chunk: vm::Chunk,
+    kind: FunctionKind,
+    arity: usize,
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: usize,
+    enclosing: Option<Box<Compiler<'h>>>,
+    // Enclosing class declarations, innermost last. Handed to nested compilers.
+    classes: Vec<ClassState>,
+    // Source position recorded for the bytes emitted next.
+    line: usize,
+    column: usize,
 }
 
-fn compile_binary(
-    chunk: &mut vm::Chunk,
-    op: &str,
-    expr_pair: &(ast::ExpressionStmt, ast::ExpressionStmt),
-) {
-    let (lhs, rhs) = expr_pair;
-    compile_expression(chunk, lhs);
-    compile_expression(chunk, rhs);
-    // TODO: refactor
-    if op.starts_with("+") {
-        chunk.emit(vm::OpCode::OpAdd);
-    } else if op.starts_with("-") {
-        chunk.emit(vm::OpCode::OpSubtract);
-    } else if op.starts_with("/") {
-        chunk.emit(vm::OpCode::OpDivide);
-    } else if op.starts_with("*") {
-        chunk.emit(vm::OpCode::OpMultiply);
-    } else {
-        panic!("opcode error");
+/// Compiles a program into its top-level function, folding its constant
+/// expressions first. Constants are allocated on `heap`, which must be the
+/// heap of the VM that runs the function.
+pub(crate) fn compile(program: &mut [ast::Statement], heap: &Heap) -> CompileResult<Function> {
+    fold::fold(program, heap);
+    let mut compiler = Compiler::new("main", FunctionKind::Script, heap);
+
+    for statement in program {
+        compiler.compile_statement(statement)?;
     }
+
+    let (function, _) = compiler.finish()?;
+    Ok(function)
 }
 
-fn compile_print_expression(chunk: &mut vm::Chunk, print_stmt: &ast::PrintStmt) {
-    compile_expression(chunk, &print_stmt.expr);
-    chunk.emit(vm::OpCode::Print);
+impl<'h> Compiler<'h> {
+    fn new(name: &str, kind: FunctionKind, heap: &'h Heap) -> Self {
+        Self {
+            heap,
+            chunk: vm::Chunk::new(name),
+            kind,
+            arity: 0,
+            // Slot zero holds the function being called, or the receiver in methods.
+            locals: vec![Local {
+                name: match kind {
+                    FunctionKind::Method | FunctionKind::Initializer => "this".into(),
+                    FunctionKind::Script | FunctionKind::Function => String::new(),
+                },
+                position: ast::Position::default(),
+                depth: Some(0),
+                is_captured: false,
+            }],
+            upvalues: Vec::new(),
+            scope_depth: 0,
+            enclosing: None,
+            classes: Vec::new(),
+            line: 1,
+            column: 1,
+        }
+    }
+
+    fn emit(&mut self, byte: impl Into<u8>) {
+        self.chunk.emit(byte, self.line, self.column);
+    }
+
+    /// Attributes the following instructions to the token at `line` and `column`.
+    fn set_position(&mut self, line: usize, column: usize) {
+        self.line = line;
+        self.column = column;
+    }
+
+    /// Returns the compiled function and the variables its closure captures.
+    fn finish(mut self) -> CompileResult<(Function, Vec<Upvalue>)> {
+        self.emit_return()?;
+        let function = match self.kind {
+            FunctionKind::Script => Function::script(self.chunk),
+            FunctionKind::Function | FunctionKind::Method | FunctionKind::Initializer => {
+                let name = self.chunk.name.clone();
+                let mut function = Function::new(&name, self.arity, self.chunk);
+                function.upvalue_count = self.upvalues.len();
+                function
+            }
+        };
+        Ok((function, self.upvalues))
+    }
+
+    fn emit_return(&mut self) -> CompileResult<()> {
+        if self.kind == FunctionKind::Initializer {
+            // Initializers always return the instance being initialized.
+            self.emit(vm::OpCode::GetLocal);
+            self.emit(0u8);
+        } else {
+            self.emit_constant(Value::nil())?;
+        }
+        self.emit(vm::OpCode::Return);
+        Ok(())
+    }
+
+    fn compile_statement(&mut self, statement: &ast::Statement) -> CompileResult<()> {
+        match statement {
+            ast::Statement::Expression(expr) => {
+                self.compile_expression(expr)?;
+                self.emit(vm::OpCode::OpPop);
+            }
+            ast::Statement::Print(print_stmt) => self.compile_print_expression(print_stmt)?,
+            ast::Statement::VarDeclaration(name, initializer) => {
+                self.compile_var_declaration(name, initializer.as_ref())?
+            }
+            ast::Statement::Block(statements) => {
+                self.begin_scope();
+                for statement in statements {
+                    self.compile_statement(statement)?;
+                }
+                self.end_scope();
+            }
+            ast::Statement::If(if_stmt) => self.compile_if(if_stmt)?,
+            ast::Statement::While(while_stmt) => self.compile_while(while_stmt)?,
+            ast::Statement::For(for_stmt) => self.compile_for(for_stmt)?,
+            ast::Statement::Function(decl) => self.compile_function_declaration(decl)?,
+            ast::Statement::Class(decl) => self.compile_class(decl)?,
+            ast::Statement::Return(return_stmt) => self.compile_return(return_stmt)?,
+        }
+        Ok(())
+    }
+
+    fn compile_expression(&mut self, expr: &ast::ExpressionStmt) -> CompileResult<()> {
+        match expr {
+            ast::ExpressionStmt::Number(x, position) => {
+                self.set_position(position.line, position.column);
+                self.emit_constant(*x)?
+            }
+            ast::ExpressionStmt::String(s, position) => {
+                self.set_position(position.line, position.column);
+                self.emit_constant(Value::string(self.heap, s))?
+            }
+            ast::ExpressionStmt::Bool(b, position) => {
+                self.set_position(position.line, position.column);
+                self.emit_constant(*b)?
+            }
+            ast::ExpressionStmt::Nil(position) => {
+                self.set_position(position.line, position.column);
+                self.emit_constant(Value::nil())?
+            }
+            ast::ExpressionStmt::Identifier(name) => {
+                self.set_position(name.line, name.column);
+                let (get_op, _, operand) = self.resolve_variable(name)?;
+                self.emit_with_operand(get_op, operand)?;
+            }
+            ast::ExpressionStmt::Assign(name, value) => {
+                self.compile_expression(value)?;
+                self.set_position(name.line, name.column);
+                let (_, set_op, operand) = self.resolve_variable(name)?;
+                self.emit_with_operand(set_op, operand)?;
+            }
+            ast::ExpressionStmt::Call(callee, arguments, paren) => {
+                self.compile_expression(callee)?;
+                for argument in arguments {
+                    self.compile_expression(argument)?;
+                }
+                self.set_position(paren.line, paren.column);
+                self.emit(vm::OpCode::Call);
+                self.emit(arguments.len() as u8);
+            }
+            ast::ExpressionStmt::Get(object, name) => {
+                self.compile_expression(object)?;
+                self.set_position(name.line, name.column);
+                let id = self.identifier_constant(&name.name);
+                self.emit_with_operand(vm::OpCode::GetProperty, id)?;
+            }
+            ast::ExpressionStmt::Set(object, name, value) => {
+                self.compile_expression(object)?;
+                self.compile_expression(value)?;
+                self.set_position(name.line, name.column);
+                let id = self.identifier_constant(&name.name);
+                self.emit_with_operand(vm::OpCode::SetProperty, id)?;
+            }
+            ast::ExpressionStmt::This(this) => {
+                if self.classes.is_empty() {
+                    return Err(Error::this_outside_class(this));
+                }
+                self.set_position(this.line, this.column);
+                let (get_op, _, operand) = self.resolve_variable(this)?;
+                self.emit_with_operand(get_op, operand)?;
+            }
+            ast::ExpressionStmt::Super(keyword, method) => {
+                match self.classes.last() {
+                    None => return Err(Error::super_outside_class(keyword)),
+                    Some(class) if !class.has_superclass => {
+                        return Err(Error::super_without_superclass(keyword));
+                    }
+                    Some(_) => {}
+                }
+                self.set_position(keyword.line, keyword.column);
+                let this = ast::Identifier {
+                    name: "this".into(),
+                    line: keyword.line,
+                    column: keyword.column,
+                };
+                let (get_op, _, operand) = self.resolve_variable(&this)?;
+                self.emit_with_operand(get_op, operand)?;
+                let (get_op, _, operand) = self.resolve_variable(keyword)?;
+                self.emit_with_operand(get_op, operand)?;
+                let id = self.identifier_constant(&method.name);
+                self.emit_with_operand(vm::OpCode::GetSuper, id)?;
+            }
+            ast::ExpressionStmt::Unary(op, expr) => self.compile_unary(op, expr)?,
+            ast::ExpressionStmt::Binary(op, expr_pair) if op.symbol == "and" => {
+                self.compile_and(op, expr_pair)?
+            }
+            ast::ExpressionStmt::Binary(op, expr_pair) if op.symbol == "or" => {
+                self.compile_or(op, expr_pair)?
+            }
+            ast::ExpressionStmt::Binary(op, expr_pair) => self.compile_binary(op, expr_pair)?,
+        }
+        Ok(())
+    }
+
+    /// Loads a constant.
+    fn emit_constant(&mut self, value: impl Into<Value>) -> CompileResult<()> {
+        let id = self.chunk.write_constant(value);
+        self.emit_with_operand(vm::OpCode::Constant, id)
+    }
+
+    /// Emits an instruction with a one-byte operand, switching to its long
+    /// form, such as `ConstantLong`, once a constant index outgrows a byte.
+    fn emit_with_operand(&mut self, instruction: vm::OpCode, operand: usize) -> CompileResult<()> {
+        if let Ok(operand) = u8::try_from(operand) {
+            self.emit(instruction);
+            self.emit(operand);
+        } else if operand <= vm::MAX_LONG_CONSTANT {
+            let long = instruction
+                .long_form()
+                .expect("only constant indices outgrow a byte");
+            self.emit(long);
+            for byte in &(operand as u32).to_be_bytes()[1..] {
+                self.emit(*byte);
+            }
+        } else {
+            return Err(Error::too_many_constants(self.line, self.column));
+        }
+        Ok(())
+    }
+
+    /// Adds the name of a variable, property or method to the constant pool.
+    fn identifier_constant(&mut self, name: &str) -> usize {
+        self.chunk.write_constant(Value::string(self.heap, name))
+    }
+
+    fn compile_unary(
+        &mut self,
+        op: &ast::Operator,
+        expr: &ast::ExpressionStmt,
+    ) -> CompileResult<()> {
+        self.compile_expression(expr)?;
+        self.set_position(op.line, op.column);
+        match op.symbol.as_str() {
+            // Negating twice leaves every number as it was, and fails like
+            // `-` on anything else.
+            "+" => {
+                self.emit(vm::OpCode::OpNegate);
+                self.emit(vm::OpCode::OpNegate);
+            }
+            "-" => self.emit(vm::OpCode::OpNegate),
+            "!" => self.emit(vm::OpCode::Not),
+            _ => panic!("opcode error"),
+        }
+        Ok(())
+    }
+
+    fn compile_binary(
+        &mut self,
+        op: &ast::Operator,
+        expr_pair: &(ast::ExpressionStmt, ast::ExpressionStmt),
+    ) -> CompileResult<()> {
+        // Walk down the left operands first, so long chains like `a + b + c`
+        // are compiled in a loop rather than one recursive call per operator.
+        let mut operations = vec![(op, &expr_pair.1)];
+        let mut lhs = &expr_pair.0;
+        while let ast::ExpressionStmt::Binary(op, expr_pair) = lhs
+            && !matches!(op.symbol.as_str(), "and" | "or")
+        {
+            operations.push((op, &expr_pair.1));
+            lhs = &expr_pair.0;
+        }
+        self.compile_expression(lhs)?;
+        for (op, rhs) in operations.into_iter().rev() {
+            self.compile_expression(rhs)?;
+            self.emit_binary_op(op);
+        }
+        Ok(())
+    }
+
+    fn emit_binary_op(&mut self, op: &ast::Operator) {
+        self.set_position(op.line, op.column);
+        match op.symbol.as_str() {
+            "+" => self.emit(vm::OpCode::OpAdd),
+            "-" => self.emit(vm::OpCode::OpSubtract),
+            "/" => self.emit(vm::OpCode::OpDivide),
+            "*" => self.emit(vm::OpCode::OpMultiply),
+            "==" => self.emit(vm::OpCode::Equal),
+            "!=" => {
+                self.emit(vm::OpCode::Equal);
+                self.emit(vm::OpCode::Not);
+            }
+            ">" => self.emit(vm::OpCode::Greater),
+            ">=" => {
+                self.emit(vm::OpCode::Less);
+                self.emit(vm::OpCode::Not);
+            }
+            "<" => self.emit(vm::OpCode::Less),
+            "<=" => {
+                self.emit(vm::OpCode::Greater);
+                self.emit(vm::OpCode::Not);
+            }
+            _ => panic!("opcode error"),
+        }
+    }
+
+    /// Leaves the left operand on the stack and skips the right one when it is falsey.
+    fn compile_and(
+        &mut self,
+        op: &ast::Operator,
+        expr_pair: &(ast::ExpressionStmt, ast::ExpressionStmt),
+    ) -> CompileResult<()> {
+        let (lhs, rhs) = expr_pair;
+        self.compile_expression(lhs)?;
+        self.set_position(op.line, op.column);
+        let end_jump = self.emit_jump(vm::OpCode::JumpIfFalse);
+        self.emit(vm::OpCode::OpPop);
+        self.compile_expression(rhs)?;
+        self.patch_jump(end_jump)
+    }
+
+    /// Leaves the left operand on the stack and skips the right one when it is truthy.
+    fn compile_or(
+        &mut self,
+        op: &ast::Operator,
+        expr_pair: &(ast::ExpressionStmt, ast::ExpressionStmt),
+    ) -> CompileResult<()> {
+        let (lhs, rhs) = expr_pair;
+        self.compile_expression(lhs)?;
+        self.set_position(op.line, op.column);
+        let else_jump = self.emit_jump(vm::OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(vm::OpCode::Jump);
+        self.patch_jump(else_jump)?;
+        self.emit(vm::OpCode::OpPop);
+        self.compile_expression(rhs)?;
+        self.patch_jump(end_jump)
+    }
+
+    fn compile_var_declaration(
+        &mut self,
+        name: &ast::Identifier,
+        initializer: Option<&ast::ExpressionStmt>,
+    ) -> CompileResult<()> {
+        self.set_position(name.line, name.column);
+        if self.scope_depth > 0 {
+            self.declare_local(name)?;
+        }
+        if let Some(expr) = initializer {
+            self.compile_expression(expr)?;
+        } else {
+            self.emit_constant(Value::nil())?;
+        }
+        self.set_position(name.line, name.column);
+        self.define_variable(name)
+    }
+
+    fn compile_function_declaration(&mut self, decl: &ast::FunctionDecl) -> CompileResult<()> {
+        self.set_position(decl.name.line, decl.name.column);
+        if self.scope_depth > 0 {
+            // Mark the local initialized right away so the body can recurse.
+            self.declare_local(&decl.name)?;
+            self.mark_initialized();
+        }
+        self.compile_function(decl, FunctionKind::Function)?;
+        self.set_position(decl.name.line, decl.name.column);
+        self.define_variable(&decl.name)
+    }
+
+    fn compile_class(&mut self, decl: &ast::ClassDecl) -> CompileResult<()> {
+        self.set_position(decl.name.line, decl.name.column);
+        if self.scope_depth > 0 {
+            self.declare_local(&decl.name)?;
+        }
+        let id = self.identifier_constant(&decl.name.name);
+        self.emit_with_operand(vm::OpCode::Class, id)?;
+        self.define_variable(&decl.name)?;
+        self.classes.push(ClassState {
+            has_superclass: decl.superclass.is_some(),
+        });
+
+        if let Some(superclass) = &decl.superclass {
+            if superclass.name == decl.name.name {
+                return Err(Error::class_inherits_from_itself(superclass));
+            }
+            // Inheriting from a non-class fails at the superclass name.
+            self.set_position(superclass.line, superclass.column);
+            let (get_op, _, operand) = self.resolve_variable(superclass)?;
+            self.emit_with_operand(get_op, operand)?;
+
+            // Keep the superclass in a local named `super` that methods capture.
+            self.begin_scope();
+            let super_name = ast::Identifier {
+                name: "super".into(),
+                line: superclass.line,
+                column: superclass.column,
+            };
+            self.declare_local(&super_name)?;
+            self.mark_initialized();
+
+            let (get_op, _, operand) = self.resolve_variable(&decl.name)?;
+            self.emit_with_operand(get_op, operand)?;
+            self.emit(vm::OpCode::Inherit);
+        }
+
+        // Load the class back so each method can be attached to it.
+        self.set_position(decl.name.line, decl.name.column);
+        let (get_op, _, operand) = self.resolve_variable(&decl.name)?;
+        self.emit_with_operand(get_op, operand)?;
+        for method in &decl.methods {
+            let kind = if method.name.name == "init" {
+                FunctionKind::Initializer
+            } else {
+                FunctionKind::Method
+            };
+            self.compile_function(method, kind)?;
+            let id = self.identifier_constant(&method.name.name);
+            self.emit_with_operand(vm::OpCode::Method, id)?;
+        }
+        self.emit(vm::OpCode::OpPop);
+
+        if decl.superclass.is_some() {
+            self.end_scope();
+        }
+        self.classes.pop();
+        Ok(())
+    }
+
+    fn compile_function(
+        &mut self,
+        decl: &ast::FunctionDecl,
+        kind: FunctionKind,
+    ) -> CompileResult<()> {
+        let mut compiler = Compiler::new(&decl.name.name, kind, self.heap);
+        compiler.classes = std::mem::take(&mut self.classes);
+        compiler.set_position(decl.name.line, decl.name.column);
+        let enclosing = std::mem::replace(self, compiler);
+        self.enclosing = Some(Box::new(enclosing));
+
+        let result = self.compile_function_body(decl);
+
+        let enclosing = self
+            .enclosing
+            .take()
+            .expect("nested compiler has no enclosing");
+        let mut compiler = std::mem::replace(self, *enclosing);
+        self.classes = std::mem::take(&mut compiler.classes);
+        result?;
+        let (function, upvalues) = compiler.finish()?;
+        self.set_position(decl.name.line, decl.name.column);
+        let id = self
+            .chunk
+            .write_constant(Value::Function(self.heap.alloc(function)));
+        self.emit_with_operand(vm::OpCode::Closure, id)?;
+        for upvalue in upvalues {
+            self.emit(upvalue.is_local as u8);
+            self.emit(upvalue.index);
+        }
+        Ok(())
+    }
+
+    fn compile_function_body(&mut self, decl: &ast::FunctionDecl) -> CompileResult<()> {
+        self.begin_scope();
+        for param in &decl.params {
+            self.declare_local(param)?;
+            self.mark_initialized();
+        }
+        self.arity = decl.params.len();
+        for statement in &decl.body {
+            self.compile_statement(statement)?;
+        }
+        Ok(())
+    }
+
+    fn compile_return(&mut self, return_stmt: &ast::ReturnStmt) -> CompileResult<()> {
+        self.set_position(return_stmt.line, return_stmt.column);
+        if self.kind == FunctionKind::Script {
+            return Err(Error::return_at_top_level(
+                return_stmt.line,
+                return_stmt.column,
+            ));
+        }
+        match &return_stmt.value {
+            Some(_) if self.kind == FunctionKind::Initializer => {
+                return Err(Error::return_value_from_initializer(
+                    return_stmt.line,
+                    return_stmt.column,
+                ));
+            }
+            Some(value) => {
+                self.compile_expression(value)?;
+                self.emit(vm::OpCode::Return);
+            }
+            None => self.emit_return()?,
+        }
+        Ok(())
+    }
+
+    /// Finishes a declaration whose value is on top of the stack.
+    fn define_variable(&mut self, name: &ast::Identifier) -> CompileResult<()> {
+        if self.scope_depth > 0 {
+            // The value stays on the stack and becomes the local's slot.
+            self.mark_initialized();
+            return Ok(());
+        }
+        let id = self.identifier_constant(&name.name);
+        self.emit_with_operand(vm::OpCode::DefineGlobal, id)?;
+        Ok(())
+    }
+
+    fn mark_initialized(&mut self) {
+        if let Some(local) = self.locals.last_mut() {
+            local.depth = Some(self.scope_depth);
+        }
+    }
+
+    fn compile_print_expression(&mut self, print_stmt: &ast::PrintStmt) -> CompileResult<()> {
+        self.compile_expression(&print_stmt.expr)?;
+        self.emit(vm::OpCode::Print);
+        Ok(())
+    }
+
+    fn compile_if(&mut self, if_stmt: &ast::IfStmt) -> CompileResult<()> {
+        self.compile_expression(&if_stmt.condition)?;
+        let then_jump = self.emit_jump(vm::OpCode::JumpIfFalse);
+        self.emit(vm::OpCode::OpPop);
+        self.compile_statement(&if_stmt.then_branch)?;
+        let else_jump = self.emit_jump(vm::OpCode::Jump);
+        self.patch_jump(then_jump)?;
+        self.emit(vm::OpCode::OpPop);
+        if let Some(else_branch) = &if_stmt.else_branch {
+            self.compile_statement(else_branch)?;
+        }
+        self.patch_jump(else_jump)
+    }
+
+    fn compile_while(&mut self, while_stmt: &ast::WhileStmt) -> CompileResult<()> {
+        let loop_start = self.chunk.code.len();
+        self.compile_expression(&while_stmt.condition)?;
+        let exit_jump = self.emit_jump(vm::OpCode::JumpIfFalse);
+        self.emit(vm::OpCode::OpPop);
+        self.compile_statement(&while_stmt.body)?;
+        self.emit_loop(loop_start)?;
+        self.patch_jump(exit_jump)?;
+        self.emit(vm::OpCode::OpPop);
+        Ok(())
+    }
+
+    fn compile_for(&mut self, for_stmt: &ast::ForStmt) -> CompileResult<()> {
+        self.begin_scope();
+        if let Some(initializer) = &for_stmt.initializer {
+            self.compile_statement(initializer)?;
+        }
+        let mut loop_start = self.chunk.code.len();
+        let exit_jump = match &for_stmt.condition {
+            Some(condition) => {
+                self.compile_expression(condition)?;
+                let exit_jump = self.emit_jump(vm::OpCode::JumpIfFalse);
+                self.emit(vm::OpCode::OpPop);
+                Some(exit_jump)
+            }
+            None => None,
+        };
+        if let Some(increment) = &for_stmt.increment {
+            // The increment runs after the body, so jump over it on the way in.
+            let body_jump = self.emit_jump(vm::OpCode::Jump);
+            let increment_start = self.chunk.code.len();
+            self.compile_expression(increment)?;
+            self.emit(vm::OpCode::OpPop);
+            self.emit_loop(loop_start)?;
+            loop_start = increment_start;
+            self.patch_jump(body_jump)?;
+        }
+        self.compile_statement(&for_stmt.body)?;
+        self.emit_loop(loop_start)?;
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump)?;
+            self.emit(vm::OpCode::OpPop);
+        }
+        self.end_scope();
+        Ok(())
+    }
+
+    /// Emits a jump with a placeholder operand and returns the operand's offset.
+    fn emit_jump(&mut self, instruction: vm::OpCode) -> usize {
+        self.emit(instruction);
+        self.emit(0xffu8);
+        self.emit(0xffu8);
+        self.chunk.code.len() - 2
+    }
+
+    /// Points the jump emitted at `offset` to the end of the current code.
+    fn patch_jump(&mut self, offset: usize) -> CompileResult<()> {
+        let jump = self.chunk.code.len() - offset - 2;
+        let jump = u16::try_from(jump).map_err(|_| self.jump_too_large(offset - 1))?;
+        self.chunk.code[offset..offset + 2].copy_from_slice(&jump.to_be_bytes());
+        Ok(())
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) -> CompileResult<()> {
+        self.emit(vm::OpCode::Loop);
+        let jump = self.chunk.code.len() + 2 - loop_start;
+        // Point at the start of the loop rather than the end of its body.
+        let jump = u16::try_from(jump).map_err(|_| self.jump_too_large(loop_start))?;
+        for byte in jump.to_be_bytes() {
+            self.emit(byte);
+        }
+        Ok(())
+    }
+
+    /// Reports a jump that can't reach its target, at the position of the
+    /// instruction at `offset`.
+    fn jump_too_large(&self, offset: usize) -> Error {
+        let (line, column) = self.chunk.position(offset).expect("the jump was emitted");
+        Error::jump_too_large(line, column)
+    }
+
+    fn begin_scope(&mut self) {
+        self.scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.scope_depth -= 1;
+        while self
+            .locals
+            .last()
+            .is_some_and(|local| local.depth.is_some_and(|depth| depth > self.scope_depth))
+        {
+            let local = self.locals.pop().expect("scope has a local");
+            if local.is_captured {
+                self.emit(vm::OpCode::CloseUpvalue);
+            } else {
+                self.emit(vm::OpCode::OpPop);
+            }
+        }
+    }
+
+    fn declare_local(&mut self, name: &ast::Identifier) -> CompileResult<()> {
+        let duplicate = self
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= self.scope_depth))
+            .find(|local| local.name == name.name);
+        if let Some(local) = duplicate {
+            return Err(Error::duplicate_variable(name, local.position));
+        }
+        if self.locals.len() > u8::MAX as usize {
+            return Err(Error::too_many_locals(name));
+        }
+        self.locals.push(Local {
+            name: name.name.clone(),
+            position: ast::Position {
+                line: name.line,
+                column: name.column,
+            },
+            depth: None,
+            is_captured: false,
+        });
+        Ok(())
+    }
+
+    fn resolve_local(&self, name: &ast::Identifier) -> CompileResult<Option<u8>> {
+        let Some((slot, local)) = self
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name.name)
+        else {
+            return Ok(None);
+        };
+        if local.depth.is_none() {
+            return Err(Error::variable_in_own_initializer(name, local.position));
+        }
+        Ok(Some(slot as u8))
+    }
+
+    /// Looks the name up in the enclosing functions, threading an upvalue
+    /// through every function between the use and the declaration.
+    fn resolve_upvalue(&mut self, name: &ast::Identifier) -> CompileResult<Option<u8>> {
+        let Some(enclosing) = self.enclosing.as_mut() else {
+            return Ok(None);
+        };
+        if let Some(slot) = enclosing.resolve_local(name)? {
+            enclosing.locals[slot as usize].is_captured = true;
+            return self.add_upvalue(name, slot, true).map(Some);
+        }
+        if let Some(index) = enclosing.resolve_upvalue(name)? {
+            return self.add_upvalue(name, index, false).map(Some);
+        }
+        Ok(None)
+    }
+
+    fn add_upvalue(
+        &mut self,
+        name: &ast::Identifier,
+        index: u8,
+        is_local: bool,
+    ) -> CompileResult<u8> {
+        if let Some(existing) = self
+            .upvalues
+            .iter()
+            .position(|upvalue| upvalue.index == index && upvalue.is_local == is_local)
+        {
+            return Ok(existing as u8);
+        }
+        if self.upvalues.len() > u8::MAX as usize {
+            return Err(Error::too_many_upvalues(name));
+        }
+        self.upvalues.push(Upvalue { index, is_local });
+        Ok((self.upvalues.len() - 1) as u8)
+    }
+
+    /// Returns the get and set instructions for a variable and their operand.
+    fn resolve_variable(
+        &mut self,
+        name: &ast::Identifier,
+    ) -> CompileResult<(vm::OpCode, vm::OpCode, usize)> {
+        if let Some(slot) = self.resolve_local(name)? {
+            return Ok((vm::OpCode::GetLocal, vm::OpCode::SetLocal, slot.into()));
+        }
+        if let Some(index) = self.resolve_upvalue(name)? {
+            return Ok((vm::OpCode::GetUpvalue, vm::OpCode::SetUpvalue, index.into()));
+        }
+        let id = self.identifier_constant(&name.name);
+        Ok((vm::OpCode::GetGlobal, vm::OpCode::SetGlobal, id))
+    }
 }
 
 #[test]
 fn tests() {
+    let heap = Heap::new();
     {
         let input = "1.25;";
         let mut parser = crate::Parser::new(input);
-        let stmt = parser.statement().unwrap();
-        let chunk = compile(&stmt);
+        let mut program = parser.statements().unwrap();
+        let chunk = compile(&mut program, &heap).unwrap().chunk;
 
         let output = format!("{:?}", chunk);
         let mut dissassembled = output.lines();
         assert_eq!(dissassembled.next(), Some("=== main ==="));
-        assert_eq!(dissassembled.next(), Some("0001 - Const 0 (1.25)"));
+        assert_eq!(dissassembled.next(), Some("0001    1 Const 0 (1.25)"));
+        assert_eq!(dissassembled.next(), Some("0003    | Pop"));
+        assert_eq!(dissassembled.next(), Some("0004    | Const 1 (nil)"));
+        assert_eq!(dissassembled.next(), Some("0006    | Return"));
+        assert_eq!(dissassembled.next(), None);
     }
     {
         let input = "-((1.25 + 3.5) / 5.75);";
         let mut parser = crate::Parser::new(input);
-        let stmt = parser.statement().unwrap();
-        let chunk = compile(&stmt);
+        let mut program = parser.statements().unwrap();
+        let chunk = compile(&mut program, &heap).unwrap().chunk;
+
+        let output = format!("{:?}", chunk);
+        let mut dissassembled = output.lines();
+        assert_eq!(dissassembled.next(), Some("=== main ==="));
+        assert_eq!(
+            dissassembled.next(),
+            Some("0001    1 Const 0 (-0.8260869565217391)")
+        );
+        assert_eq!(dissassembled.next(), Some("0003    | Pop"));
+    }
+    {
+        let input = "print 1;\n1 + 2;\nprint 3 * 4;";
+        let mut parser = crate::Parser::new(input);
+        let mut program = parser.statements().unwrap();
+        assert_eq!(program.len(), 3);
+        let chunk = compile(&mut program, &heap).unwrap().chunk;
 
         let output = format!("{:?}", chunk);
         let mut dissassembled = output.lines();
         assert_eq!(dissassembled.next(), Some("=== main ==="));
-        assert_eq!(dissassembled.next(), Some("0001 - Const 0 (1.25)"));
-        assert_eq!(dissassembled.next(), Some("0003 - Const 1 (3.5)"));
-        assert_eq!(dissassembled.next(), Some("0005 - Add"));
-        assert_eq!(dissassembled.next(), Some("0006 - Const 2 (5.75)"));
-        assert_eq!(dissassembled.next(), Some("0008 - Div"));
-        assert_eq!(dissassembled.next(), Some("0009 - Neg"));
+        assert_eq!(dissassembled.next(), Some("0001    1 Const 0 (1)"));
+        assert_eq!(dissassembled.next(), Some("0003    | Print"));
+        assert_eq!(dissassembled.next(), Some("0004    2 Const 1 (3)"));
+        assert_eq!(dissassembled.next(), Some("0006    | Pop"));
+        assert_eq!(dissassembled.next(), Some("0007    3 Const 2 (12)"));
+        assert_eq!(dissassembled.next(), Some("0009    | Print"));
+        assert_eq!(dissassembled.next(), Some("0010    | Const 3 (nil)"));
+        assert_eq!(dissassembled.next(), Some("0012    | Return"));
+        assert_eq!(dissassembled.next(), None);
+    }
+    {
+        let input = "var a = 1; var b; b = a;";
+        let mut parser = crate::Parser::new(input);
+        let mut program = parser.statements().unwrap();
+        let chunk = compile(&mut program, &heap).unwrap().chunk;
+
+        let output = format!("{:?}", chunk);
+        let mut dissassembled = output.lines();
+        assert_eq!(dissassembled.next(), Some("=== main ==="));
+        assert_eq!(dissassembled.next(), Some("0001    1 Const 0 (1)"));
+        assert_eq!(dissassembled.next(), Some("0003    | DefGlobal 1 (a)"));
+        assert_eq!(dissassembled.next(), Some("0005    | Const 2 (nil)"));
+        assert_eq!(dissassembled.next(), Some("0007    | DefGlobal 3 (b)"));
+        assert_eq!(dissassembled.next(), Some("0009    | GetGlobal 1 (a)"));
+        assert_eq!(dissassembled.next(), Some("0011    | SetGlobal 3 (b)"));
+        assert_eq!(dissassembled.next(), Some("0013    | Pop"));
+        assert_eq!(dissassembled.next(), Some("0014    | Const 2 (nil)"));
+        assert_eq!(dissassembled.next(), Some("0016    | Return"));
+    }
+    {
+        let input = "var a = 1; { var a = a; }";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        assert!(matches!(
+            compile(&mut program, &heap),
+            Err(Error::VariableInOwnInitializer {
+                name,
+                line: 1,
+                column: 22,
+                declared_line: 1,
+                declared_column: 18
+            }) if name == "a"
+        ));
+
+        let input = "{\n  var a = 1;\n  var a = 2;\n}";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        assert!(matches!(
+            compile(&mut program, &heap),
+            Err(Error::DuplicateVariable {
+                name,
+                line: 3,
+                column: 7,
+                declared_line: 2,
+                declared_column: 7
+            }) if name == "a"
+        ));
+
+        let input = "{ var a = 1; { var a = 2; } }";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        assert!(compile(&mut program, &heap).is_ok());
+    }
+    {
+        let input = "{ var a = 1; { var b = a; b = 2; } a; }";
+        let mut parser = crate::Parser::new(input);
+        let mut program = parser.statements().unwrap();
+        let chunk = compile(&mut program, &heap).unwrap().chunk;
+
+        let output = format!("{:?}", chunk);
+        let mut dissassembled = output.lines();
+        assert_eq!(dissassembled.next(), Some("=== main ==="));
+        assert_eq!(dissassembled.next(), Some("0001    1 Const 0 (1)"));
+        assert_eq!(dissassembled.next(), Some("0003    | GetLocal 1"));
+        assert_eq!(dissassembled.next(), Some("0005    | Const 1 (2)"));
+        assert_eq!(dissassembled.next(), Some("0007    | SetLocal 2"));
+        assert_eq!(dissassembled.next(), Some("0009    | Pop"));
+        assert_eq!(dissassembled.next(), Some("0010    | Pop"));
+        assert_eq!(dissassembled.next(), Some("0011    | GetLocal 1"));
+        assert_eq!(dissassembled.next(), Some("0013    | Pop"));
+        assert_eq!(dissassembled.next(), Some("0014    | Pop"));
+        assert_eq!(dissassembled.next(), Some("0015    | Const 2 (nil)"));
+        assert_eq!(dissassembled.next(), Some("0017    | Return"));
+    }
+    {
+        let input = "if (1) print 2; else print 3;";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let chunk = compile(&mut program, &heap).unwrap().chunk;
+
+        let output = format!("{:?}", chunk);
+        let mut dissassembled = output.lines();
+        assert_eq!(dissassembled.next(), Some("=== main ==="));
+        assert_eq!(dissassembled.next(), Some("0001    1 Const 0 (1)"));
+        assert_eq!(
+            dissassembled.next(),
+            Some("0003    | JumpIfFalse 7 -> 0013")
+        );
+        assert_eq!(dissassembled.next(), Some("0006    | Pop"));
+        assert_eq!(dissassembled.next(), Some("0007    | Const 1 (2)"));
+        assert_eq!(dissassembled.next(), Some("0009    | Print"));
+        assert_eq!(dissassembled.next(), Some("0010    | Jump 4 -> 0017"));
+        assert_eq!(dissassembled.next(), Some("0013    | Pop"));
+        assert_eq!(dissassembled.next(), Some("0014    | Const 2 (3)"));
+        assert_eq!(dissassembled.next(), Some("0016    | Print"));
+        assert_eq!(dissassembled.next(), Some("0017    | Const 3 (nil)"));
+        assert_eq!(dissassembled.next(), Some("0019    | Return"));
+    }
+    {
+        let input = "while (1) print 2;";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let chunk = compile(&mut program, &heap).unwrap().chunk;
+
+        let output = format!("{:?}", chunk);
+        let mut dissassembled = output.lines();
+        assert_eq!(dissassembled.next(), Some("=== main ==="));
+        assert_eq!(dissassembled.next(), Some("0001    1 Const 0 (1)"));
+        assert_eq!(
+            dissassembled.next(),
+            Some("0003    | JumpIfFalse 7 -> 0013")
+        );
+        assert_eq!(dissassembled.next(), Some("0006    | Pop"));
+        assert_eq!(dissassembled.next(), Some("0007    | Const 1 (2)"));
+        assert_eq!(dissassembled.next(), Some("0009    | Print"));
+        assert_eq!(dissassembled.next(), Some("0010    | Loop 12 -> 0001"));
+        assert_eq!(dissassembled.next(), Some("0013    | Pop"));
+        assert_eq!(dissassembled.next(), Some("0014    | Const 2 (nil)"));
+        assert_eq!(dissassembled.next(), Some("0016    | Return"));
+    }
+    {
+        let input = "!(1 <= 2) != \"a\" >= nil;";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let chunk = compile(&mut program, &heap).unwrap().chunk;
+
+        let output = format!("{:?}", chunk);
+        let mut dissassembled = output.lines();
+        assert_eq!(dissassembled.next(), Some("=== main ==="));
+        // `!(1 <= 2)` folds, but comparing a string with nil is left to fail at runtime.
+        assert_eq!(dissassembled.next(), Some("0001    1 Const 0 (false)"));
+        assert_eq!(dissassembled.next(), Some("0003    | Const 1 (a)"));
+        assert_eq!(dissassembled.next(), Some("0005    | Const 2 (nil)"));
+        assert_eq!(dissassembled.next(), Some("0007    | Less"));
+        assert_eq!(dissassembled.next(), Some("0008    | Not"));
+        assert_eq!(dissassembled.next(), Some("0009    | Equal"));
+        assert_eq!(dissassembled.next(), Some("0010    | Not"));
+        assert_eq!(dissassembled.next(), Some("0011    | Pop"));
+
+        let input = "a <= b;";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let chunk = compile(&mut program, &heap).unwrap().chunk;
+        let output = format!("{:?}", chunk);
+        let mut dissassembled = output.lines().skip(3);
+        assert_eq!(dissassembled.next(), Some("0005    | Greater"));
+        assert_eq!(dissassembled.next(), Some("0006    | Not"));
+    }
+    {
+        let input = "a and b or c;";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let chunk = compile(&mut program, &heap).unwrap().chunk;
+
+        let output = format!("{:?}", chunk);
+        let mut dissassembled = output.lines();
+        assert_eq!(dissassembled.next(), Some("=== main ==="));
+        assert_eq!(dissassembled.next(), Some("0001    1 GetGlobal 0 (a)"));
+        assert_eq!(
+            dissassembled.next(),
+            Some("0003    | JumpIfFalse 3 -> 0009")
+        );
+        assert_eq!(dissassembled.next(), Some("0006    | Pop"));
+        assert_eq!(dissassembled.next(), Some("0007    | GetGlobal 1 (b)"));
+        assert_eq!(
+            dissassembled.next(),
+            Some("0009    | JumpIfFalse 3 -> 0015")
+        );
+        assert_eq!(dissassembled.next(), Some("0012    | Jump 3 -> 0018"));
+        assert_eq!(dissassembled.next(), Some("0015    | Pop"));
+        assert_eq!(dissassembled.next(), Some("0016    | GetGlobal 2 (c)"));
+        assert_eq!(dissassembled.next(), Some("0018    | Pop"));
+    }
+    {
+        let input = "fun add(a, b) { return a + b; } print add(1, 2);";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let function = compile(&mut program, &heap).unwrap();
+
+        let output = format!("{:?}", function.chunk);
+        let mut dissassembled = output.lines();
+        assert_eq!(dissassembled.next(), Some("=== main ==="));
+        assert_eq!(dissassembled.next(), Some("0001    1 Closure 0 (<fn add>)"));
+        assert_eq!(dissassembled.next(), Some("0003    | DefGlobal 1 (add)"));
+        assert_eq!(dissassembled.next(), Some("0005    | GetGlobal 1 (add)"));
+        assert_eq!(dissassembled.next(), Some("0007    | Const 2 (1)"));
+        assert_eq!(dissassembled.next(), Some("0009    | Const 3 (2)"));
+        assert_eq!(dissassembled.next(), Some("0011    | Call 2"));
+        assert_eq!(dissassembled.next(), Some("0013    | Print"));
+
+        let Value::Function(add) = &function.chunk.constants[0] else {
+            panic!("expected a function constant");
+        };
+        assert_eq!(add.arity, 2);
+        let output = format!("{:?}", add.chunk);
+        let mut dissassembled = output.lines();
+        assert_eq!(dissassembled.next(), Some("=== add ==="));
+        assert_eq!(dissassembled.next(), Some("0001    1 GetLocal 1"));
+        assert_eq!(dissassembled.next(), Some("0003    | GetLocal 2"));
+        assert_eq!(dissassembled.next(), Some("0005    | Add"));
+        assert_eq!(dissassembled.next(), Some("0006    | Return"));
+        assert_eq!(dissassembled.next(), Some("0007    | Const 0 (nil)"));
+        assert_eq!(dissassembled.next(), Some("0009    | Return"));
+    }
+    {
+        let mut program = crate::Parser::new("return 1;").statements().unwrap();
+        assert!(matches!(
+            compile(&mut program, &heap),
+            Err(Error::ReturnAtTopLevel { line: 1, column: 1 })
+        ));
+    }
+    {
+        let input = "
+            fun outer() {
+                var x = 1;
+                var y = 2;
+                fun middle() {
+                    fun inner() { return x + y; }
+                    return inner;
+                }
+                return middle;
+            }
+        ";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let function = compile(&mut program, &heap).unwrap();
+
+        let Value::Function(outer) = &function.chunk.constants[0] else {
+            panic!("expected a function constant");
+        };
+        let output = format!("{:?}", outer.chunk);
+        let mut dissassembled = output.lines();
+        assert_eq!(dissassembled.next(), Some("=== outer ==="));
+        assert_eq!(dissassembled.next(), Some("0001    3 Const 0 (1)"));
+        assert_eq!(dissassembled.next(), Some("0003    4 Const 1 (2)"));
+        assert_eq!(
+            dissassembled.next(),
+            Some("0005    5 Closure 2 (<fn middle>)")
+        );
+        assert_eq!(dissassembled.next(), Some("0007    |   local 1"));
+        assert_eq!(dissassembled.next(), Some("0009    |   local 2"));
+        assert_eq!(dissassembled.next(), Some("0011    9 GetLocal 3"));
+        assert_eq!(dissassembled.next(), Some("0013    | Return"));
+
+        let Value::Function(middle) = &outer.chunk.constants[2] else {
+            panic!("expected a function constant");
+        };
+        assert_eq!(middle.upvalue_count, 2);
+        let output = format!("{:?}", middle.chunk);
+        let mut dissassembled = output.lines();
+        assert_eq!(dissassembled.next(), Some("=== middle ==="));
+        assert_eq!(
+            dissassembled.next(),
+            Some("0001    6 Closure 0 (<fn inner>)")
+        );
+        assert_eq!(dissassembled.next(), Some("0003    |   upvalue 0"));
+        assert_eq!(dissassembled.next(), Some("0005    |   upvalue 1"));
+
+        let Value::Function(inner) = &middle.chunk.constants[0] else {
+            panic!("expected a function constant");
+        };
+        let output = format!("{:?}", inner.chunk);
+        let mut dissassembled = output.lines();
+        assert_eq!(dissassembled.next(), Some("=== inner ==="));
+        assert_eq!(dissassembled.next(), Some("0001    6 GetUpvalue 0"));
+        assert_eq!(dissassembled.next(), Some("0003    | GetUpvalue 1"));
+        assert_eq!(dissassembled.next(), Some("0005    | Add"));
+    }
+    {
+        let input = "{ var a = 1; fun f() { return a; } }";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let chunk = compile(&mut program, &heap).unwrap().chunk;
+
+        let output = format!("{:?}", chunk);
+        let mut dissassembled = output.lines();
+        assert_eq!(dissassembled.next(), Some("=== main ==="));
+        assert_eq!(dissassembled.next(), Some("0001    1 Const 0 (1)"));
+        assert_eq!(dissassembled.next(), Some("0003    | Closure 1 (<fn f>)"));
+        assert_eq!(dissassembled.next(), Some("0005    |   local 1"));
+        assert_eq!(dissassembled.next(), Some("0007    | Pop"));
+        assert_eq!(dissassembled.next(), Some("0008    | CloseUpvalue"));
+    }
+    {
+        let input = "class Point { init(x) { this.x = x; return; } getX() { return this.x; } }";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let function = compile(&mut program, &heap).unwrap();
+
+        let output = format!("{:?}", function.chunk);
+        let mut dissassembled = output.lines();
+        assert_eq!(dissassembled.next(), Some("=== main ==="));
+        assert_eq!(dissassembled.next(), Some("0001    1 Class 0 (Point)"));
+        assert_eq!(dissassembled.next(), Some("0003    | DefGlobal 0 (Point)"));
+        assert_eq!(dissassembled.next(), Some("0005    | GetGlobal 0 (Point)"));
+        assert_eq!(
+            dissassembled.next(),
+            Some("0007    | Closure 1 (<fn init>)")
+        );
+        assert_eq!(dissassembled.next(), Some("0009    | Method 2 (init)"));
+        assert_eq!(
+            dissassembled.next(),
+            Some("0011    | Closure 3 (<fn getX>)")
+        );
+        assert_eq!(dissassembled.next(), Some("0013    | Method 4 (getX)"));
+        assert_eq!(dissassembled.next(), Some("0015    | Pop"));
+
+        let Value::Function(init) = &function.chunk.constants[1] else {
+            panic!("expected a function constant");
+        };
+        let output = format!("{:?}", init.chunk);
+        let mut dissassembled = output.lines();
+        assert_eq!(dissassembled.next(), Some("=== init ==="));
+        assert_eq!(dissassembled.next(), Some("0001    1 GetLocal 0"));
+        assert_eq!(dissassembled.next(), Some("0003    | GetLocal 1"));
+        assert_eq!(dissassembled.next(), Some("0005    | SetProperty 0 (x)"));
+        assert_eq!(dissassembled.next(), Some("0007    | Pop"));
+        assert_eq!(dissassembled.next(), Some("0008    | GetLocal 0"));
+        assert_eq!(dissassembled.next(), Some("0010    | Return"));
+        assert_eq!(dissassembled.next(), Some("0011    | GetLocal 0"));
+        assert_eq!(dissassembled.next(), Some("0013    | Return"));
+    }
+    {
+        let mut program = crate::Parser::new("fun f() { return this; }")
+            .statements()
+            .unwrap();
+        assert!(matches!(
+            compile(&mut program, &heap),
+            Err(Error::ThisOutsideClass {
+                line: 1,
+                column: 18
+            })
+        ));
+
+        let input = "class A { init() { return 1; } }";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        assert!(matches!(
+            compile(&mut program, &heap),
+            Err(Error::ReturnValueFromInitializer {
+                line: 1,
+                column: 20
+            })
+        ));
+
+        let input = "class A { method() { fun inner() { return this; } } }";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        assert!(compile(&mut program, &heap).is_ok());
+    }
+    {
+        let input = "class A { m() {} } class B < A { m() { return super.m; } }";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let function = compile(&mut program, &heap).unwrap();
+
+        let output = format!("{:?}", function.chunk);
+        let mut dissassembled = output.lines().skip(7);
+        assert_eq!(dissassembled.next(), Some("0012    | Class 3 (B)"));
+        assert_eq!(dissassembled.next(), Some("0014    | DefGlobal 3 (B)"));
+        assert_eq!(dissassembled.next(), Some("0016    | GetGlobal 0 (A)"));
+        assert_eq!(dissassembled.next(), Some("0018    | GetGlobal 3 (B)"));
+        assert_eq!(dissassembled.next(), Some("0020    | Inherit"));
+        assert_eq!(dissassembled.next(), Some("0021    | GetGlobal 3 (B)"));
+        assert_eq!(dissassembled.next(), Some("0023    | Closure 4 (<fn m>)"));
+        assert_eq!(dissassembled.next(), Some("0025    |   local 1"));
+        assert_eq!(dissassembled.next(), Some("0027    | Method 2 (m)"));
+        assert_eq!(dissassembled.next(), Some("0029    | Pop"));
+        assert_eq!(dissassembled.next(), Some("0030    | CloseUpvalue"));
+
+        let Value::Function(method) = &function.chunk.constants[4] else {
+            panic!("expected a function constant");
+        };
+        let output = format!("{:?}", method.chunk);
+        let mut dissassembled = output.lines();
+        assert_eq!(dissassembled.next(), Some("=== m ==="));
+        assert_eq!(dissassembled.next(), Some("0001    1 GetLocal 0"));
+        assert_eq!(dissassembled.next(), Some("0003    | GetUpvalue 0"));
+        assert_eq!(dissassembled.next(), Some("0005    | GetSuper 0 (m)"));
+    }
+    {
+        let mut program = crate::Parser::new("class A < A {}").statements().unwrap();
+        assert!(matches!(
+            compile(&mut program, &heap),
+            Err(Error::ClassInheritsFromItself { name, line: 1, column: 11 }) if name == "A"
+        ));
+
+        let mut program = crate::Parser::new("fun f() { super.m(); }")
+            .statements()
+            .unwrap();
+        assert!(matches!(
+            compile(&mut program, &heap),
+            Err(Error::SuperOutsideClass {
+                line: 1,
+                column: 11
+            })
+        ));
+
+        let input = "class A { m() { super.m(); } }";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        assert!(matches!(
+            compile(&mut program, &heap),
+            Err(Error::SuperWithoutSuperclass {
+                line: 1,
+                column: 17
+            })
+        ));
+    }
+    {
+        // The 257th distinct constant switches to the long form. Starting
+        // with a variable keeps the sum from being folded.
+        let mut terms: Vec<String> = (0..300).map(|i| i.to_string()).collect();
+        terms[0] = "x".into();
+        let input = format!("print {};", terms.join(" + "));
+        let mut program = crate::Parser::new(&input).statements().unwrap();
+        let chunk = compile(&mut program, &heap).unwrap().chunk;
+
+        let output = format!("{:?}", chunk);
+        let mut dissassembled = output.lines().skip(2 + 254 * 2);
+        assert_eq!(dissassembled.next(), Some("0765    | Const 255 (255)"));
+        assert_eq!(dissassembled.next(), Some("0767    | Add"));
+        assert_eq!(dissassembled.next(), Some("0768    | ConstLong 256 (256)"));
+        assert_eq!(dissassembled.next(), Some("0772    | Add"));
+        assert_eq!(dissassembled.next(), Some("0773    | ConstLong 257 (257)"));
+    }
+    {
+        // Instructions naming a constant switch to long forms too.
+        let mut input: String = (0..300).map(|i| format!("var v{i} = {i};")).collect();
+        input += "class C { m() { return this.f; } } var c = C(); c.f = v299; var r = c.m();";
+        let mut program = crate::Parser::new(&input).statements().unwrap();
+        let heap = Heap::new();
+        let function = compile(&mut program, &heap).unwrap();
+        crate::verify::verify(&function).unwrap();
+        let output = format!("{:?}", function.chunk);
+        assert!(output.contains("DefGlobalLong 599 (v299)"));
+        assert!(output.contains("ClassLong"));
+        assert!(output.contains("SetPropertyLong"));
+
+        let mut vm = crate::vm::VM::new(function, heap);
+        vm.interpret().unwrap();
+        assert!(matches!(vm.global("v0"), Some(Value::Number(0.0))));
+        assert!(matches!(vm.global("r"), Some(Value::Number(299.0))));
+    }
+    {
+        let input = "print x + 1 + 1; print x + \"a\" + \"a\";";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let chunk = compile(&mut program, &heap).unwrap().chunk;
+        assert_eq!(chunk.constants.len(), 4);
+
+        let output = format!("{:?}", chunk);
+        let mut dissassembled = output.lines().skip(2);
+        assert_eq!(dissassembled.next(), Some("0003    | Const 1 (1)"));
+        assert_eq!(dissassembled.next(), Some("0005    | Add"));
+        assert_eq!(dissassembled.next(), Some("0006    | Const 1 (1)"));
+        assert_eq!(dissassembled.next(), Some("0008    | Add"));
+        assert_eq!(dissassembled.next(), Some("0009    | Print"));
+        assert_eq!(dissassembled.next(), Some("0010    | GetGlobal 0 (x)"));
+        assert_eq!(dissassembled.next(), Some("0012    | Const 2 (a)"));
+        assert_eq!(dissassembled.next(), Some("0014    | Add"));
+        assert_eq!(dissassembled.next(), Some("0015    | Const 2 (a)"));
+    }
+    {
+        // Constant expressions fold to one constant, with the same results
+        // the VM would produce.
+        let fold = |input: &str| {
+            let mut program = crate::Parser::new(input).statements().unwrap();
+            let chunk = compile(&mut program, &heap).unwrap().chunk;
+            let output = format!("{:?}", chunk);
+            output.lines().nth(1).unwrap().to_string()
+        };
+        assert_eq!(
+            fold("print \"a\" + \"b\" + \"c\";"),
+            "0001    1 Const 0 (abc)"
+        );
+        // Only the final string is interned.
+        assert_eq!(heap.find_string("ab"), None);
+        let input = format!("print {};", vec!["\"a\""; 10_000].join(" + "));
+        assert!(fold(&input).ends_with("aaa)"));
+        assert_eq!(fold("print !(1 < 2 == true);"), "0001    1 Const 0 (false)");
+        assert_eq!(fold("print 2 >= 2 and 1 / 4;"), "0001    1 Const 0 (0.25)");
+        assert_eq!(fold("print nil or -0;"), "0001    1 Const 0 (-0)");
+        assert_eq!(fold("print \"a\" != nil;"), "0001    1 Const 0 (true)");
+        // The constant part at the start of a chain folds, the rest runs.
+        let mut program = crate::Parser::new("print 1 + 2 + x;").statements().unwrap();
+        let chunk = compile(&mut program, &heap).unwrap().chunk;
+        let output = format!("{:?}", chunk);
+        let mut dissassembled = output.lines().skip(1);
+        assert_eq!(dissassembled.next(), Some("0001    1 Const 0 (3)"));
+        assert_eq!(dissassembled.next(), Some("0003    | GetGlobal 1 (x)"));
+        assert_eq!(dissassembled.next(), Some("0005    | Add"));
+
+        // Type errors are still reported at runtime, at the operator.
+        let mut program = crate::Parser::new("print 2 * (1 + \"a\");")
+            .statements()
+            .unwrap();
+        let heap = Heap::new();
+        let function = compile(&mut program, &heap).unwrap();
+        let mut vm = crate::vm::VM::new(function, heap);
+        assert!(matches!(
+            vm.interpret(),
+            Err(Error::Runtime { line: 1, column: 14, error, .. })
+                if matches!(*error, Error::TypeError { .. })
+        ));
     }
 }
@@ -0,0 +1,440 @@
+use crate::Lexer;
+use crate::error::Error;
+use std::fmt::Write;
+
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// A message attached to a place in the source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Label {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl Label {
+    pub fn new(line: usize, column: usize, message: impl Into<String>) -> Self {
+        Self {
+            line,
+            column,
+            message: message.into(),
+        }
+    }
+
+    /// The line and column the label points at, counting characters rather
+    /// than the bytes the lexer counts.
+    pub fn start(&self, source: &str) -> (usize, usize) {
+        (self.line, char_column(source, self.line, self.column))
+    }
+
+    /// The line and column just past the token the label points at, which is
+    /// on a later line for strings that span lines.
+    pub fn end(&self, source: &str) -> (usize, usize) {
+        let (line, column) = self.start(source);
+        match token_at(source, self.line, self.column) {
+            Some(lexeme) => match lexeme.rsplit_once('\n') {
+                Some((before, last)) => (
+                    line + before.matches('\n').count() + 1,
+                    last.chars().count() + 1,
+                ),
+                None => (line, column + lexeme.chars().count()),
+            },
+            None => (line, column + 1),
+        }
+    }
+}
+
+/// An error prepared for display: the message, where it happened, related
+/// places in the source and any extra notes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub code: &'static str,
+    pub message: String,
+    pub primary: Option<Label>,
+    pub secondary: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl From<&Error> for Diagnostic {
+    fn from(error: &Error) -> Self {
+        let primary = error.location().map(|(line, column)| {
+            let message = match error {
+                Error::UnexpectedChar { .. } => "unexpected character".into(),
+                Error::UnterminatedString { .. } => "string starts here".into(),
+                Error::UnexpectedToken {
+                    expected: Some(expected),
+                    ..
+                } => format!("expected {expected:?}"),
+                Error::UnexpectedToken { expected: None, .. } => "expected an expression".into(),
+                Error::InvalidAssignmentTarget { .. } => "can't assign to this".into(),
+                Error::VariableInOwnInitializer { .. } => "read here".into(),
+                Error::DuplicateVariable { .. } => "declared again here".into(),
+                _ => String::new(),
+            };
+            Label::new(line, column, message)
+        });
+
+        let mut secondary = Vec::new();
+        let mut notes = Vec::new();
+        match error {
+            Error::VariableInOwnInitializer {
+                declared_line,
+                declared_column,
+                ..
+            } => secondary.push(Label::new(
+                *declared_line,
+                *declared_column,
+                "variable declared here",
+            )),
+            Error::DuplicateVariable {
+                declared_line,
+                declared_column,
+                ..
+            } => secondary.push(Label::new(
+                *declared_line,
+                *declared_column,
+                "first declared here",
+            )),
+            Error::ReturnValueFromInitializer { .. } => {
+                notes.push("initializers always return 'this'".into())
+            }
+            Error::SuperWithoutSuperclass { .. } => {
+                notes.push("declare a superclass with 'class Name < Superclass'".into())
+            }
+            Error::Runtime { trace, .. } if !trace.is_empty() => {
+                let mut note = String::from("stack trace, innermost call first:");
+                for frame in trace {
+                    write!(note, "\n{frame}").unwrap();
+                }
+                notes.push(note);
+            }
+            _ => {}
+        }
+
+        Self {
+            code: error.code(),
+            message: error.message(),
+            primary,
+            secondary,
+            notes,
+        }
+    }
+}
+
+impl Diagnostic {
+    /// Renders the diagnostic with the labelled lines of `source` underlined,
+    /// using ANSI colours if `colour` is set.
+    pub fn render(&self, source: &str, filename: &str, colour: bool) -> String {
+        let paint = |style: &str, text: &str| {
+            if colour {
+                format!("{style}{text}{RESET}")
+            } else {
+                text.to_string()
+            }
+        };
+        let mut out = String::new();
+        writeln!(
+            out,
+            "{}{}",
+            paint(RED, &format!("error[{}]", self.code)),
+            paint(BOLD, &format!(": {}", self.message))
+        )
+        .unwrap();
+
+        let mut labels: Vec<(&Label, bool)> =
+            self.primary.iter().map(|label| (label, true)).collect();
+        labels.extend(self.secondary.iter().map(|label| (label, false)));
+        labels.sort_by_key(|(label, _)| (label.line, label.column));
+        let gutter = labels
+            .iter()
+            .map(|(label, _)| label.line.to_string().len())
+            .max()
+            .unwrap_or(0);
+        let pipe = paint(BLUE, &format!("{:gutter$} |", ""));
+
+        match &self.primary {
+            Some(primary) => {
+                let (line, column) = primary.start(source);
+                writeln!(
+                    out,
+                    "{:gutter$}{} {filename}:{line}:{column}",
+                    "",
+                    paint(BLUE, "-->"),
+                )
+                .unwrap()
+            }
+            None => writeln!(out, "{:gutter$}{} {filename}", "", paint(BLUE, "-->")).unwrap(),
+        }
+
+        let lines: Vec<&str> = source.lines().collect();
+        // Without the source, as for bytecode files, there is nothing to quote.
+        if labels
+            .iter()
+            .any(|(label, _)| label.line.wrapping_sub(1) < lines.len())
+        {
+            writeln!(out, "{pipe}").unwrap();
+        }
+        let mut previous_line = None;
+        for (label, is_primary) in &labels {
+            let Some(text) = lines.get(label.line.wrapping_sub(1)) else {
+                continue;
+            };
+            if previous_line != Some(label.line) {
+                if previous_line.is_some_and(|previous| label.line > previous + 1) {
+                    writeln!(out, "{}", paint(BLUE, "...")).unwrap();
+                }
+                let number = paint(BLUE, &format!("{:>gutter$} |", label.line));
+                writeln!(out, "{number} {text}").unwrap();
+                previous_line = Some(label.line);
+            }
+            // Keep tabs so the underline lines up with the source.
+            let indent: String = text
+                .get(..label.column.saturating_sub(1))
+                .unwrap_or(text)
+                .chars()
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            // Tokens spanning lines are underlined on their first line.
+            let width = token_at(source, label.line, label.column)
+                .and_then(|lexeme| lexeme.lines().next())
+                .map_or(1, |first| first.chars().count().max(1));
+            let (marker, style) = if *is_primary { ('^', RED) } else { ('-', BLUE) };
+            let underline = format!("{} {}", marker.to_string().repeat(width), label.message);
+            writeln!(out, "{pipe} {indent}{}", paint(style, underline.trim_end())).unwrap();
+        }
+
+        for note in &self.notes {
+            let mut note_lines = note.lines();
+            let first = note_lines.next().unwrap_or_default();
+            writeln!(out, "{:gutter$} {} {first}", "", paint(BOLD, "= note:")).unwrap();
+            for line in note_lines {
+                writeln!(out, "{:gutter$}         {line}", "").unwrap();
+            }
+        }
+        out
+    }
+}
+
+impl Diagnostic {
+    /// Renders the diagnostic as a single line of JSON for tools to consume.
+    /// Spans start at the labelled token and end just past it. Lines and
+    /// columns count from 1, and columns count characters, as in `render`.
+    pub fn to_json(&self, source: &str, filename: &str) -> String {
+        let span = |label: Option<&Label>| match label {
+            Some(label) => {
+                let (start_line, start_column) = label.start(source);
+                let (end_line, end_column) = label.end(source);
+                format!(
+                    r#""start_line":{start_line},"start_column":{start_column},"end_line":{end_line},"end_column":{end_column}"#
+                )
+            }
+            None => {
+                r#""start_line":null,"start_column":null,"end_line":null,"end_column":null"#.into()
+            }
+        };
+        let labels: Vec<String> = self
+            .primary
+            .iter()
+            .map(|label| (label, true))
+            .chain(self.secondary.iter().map(|label| (label, false)))
+            .map(|(label, primary)| {
+                format!(
+                    r#"{{"message":{},{},"primary":{primary}}}"#,
+                    json_string(&label.message),
+                    span(Some(label))
+                )
+            })
+            .collect();
+        let notes: Vec<String> = self.notes.iter().map(|note| json_string(note)).collect();
+        format!(
+            r#"{{"severity":"error","code":{},"message":{},"file":{},{},"labels":[{}],"notes":[{}]}}"#,
+            json_string(self.code),
+            json_string(&self.message),
+            json_string(filename),
+            span(self.primary.as_ref()),
+            labels.join(","),
+            notes.join(",")
+        )
+    }
+}
+
+/// Quotes and escapes `s` as a JSON string.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// The byte offset at which the 1-based `line` of `source` starts.
+fn line_start(source: &str, line: usize) -> Option<usize> {
+    if line == 0 {
+        return None;
+    }
+    let mut start = 0;
+    for _ in 1..line {
+        start += source[start..].find('\n')? + 1;
+    }
+    Some(start)
+}
+
+/// Converts a byte column, as the lexer counts them, to a character column.
+fn char_column(source: &str, line: usize, column: usize) -> usize {
+    line_start(source, line)
+        .and_then(|start| source.get(start..start + column.saturating_sub(1)))
+        .filter(|before| !before.contains('\n'))
+        .map_or(column, |before| before.chars().count() + 1)
+}
+
+/// The text of the token starting at the given line and byte column, if any.
+fn token_at(source: &str, line: usize, column: usize) -> Option<&str> {
+    let start = line_start(source, line)? + column.checked_sub(1)?;
+    match Lexer::new(source.get(start..)?).next() {
+        Some(Ok(token)) if !token.lexeme.is_empty() => Some(token.lexeme),
+        _ => None,
+    }
+}
+
+#[test]
+fn tests() {
+    {
+        let source = "var a = 1;\nprint a +;\n";
+        let errors = crate::Parser::new(source).statements().err().unwrap();
+        let rendered = Diagnostic::from(&errors[0]).render(source, "test.lox", false);
+        assert_eq!(
+            rendered,
+            "error[P0002]: Unexpected token: Semicolon\n \
+             --> test.lox:2:10\n  \
+             |\n\
+             2 | print a +;\n  \
+             |          ^ expected an expression\n"
+        );
+    }
+    {
+        // Secondary labels point back at the first declaration, and the
+        // underline spans the whole token.
+        let source = "{\n  var count = 1;\n\n  var count = 2;\n}";
+        let mut program = crate::Parser::new(source).statements().unwrap();
+        let heap = crate::gc::Heap::new();
+        let error = crate::compiler::compile(&mut program, &heap).err().unwrap();
+        let rendered = Diagnostic::from(&error).render(source, "test.lox", false);
+        assert_eq!(
+            rendered,
+            "error[C0002]: Variable 'count' is already declared in this scope\n \
+             --> test.lox:4:7\n  \
+             |\n\
+             2 |   var count = 1;\n  \
+             |       ----- first declared here\n\
+             ...\n\
+             4 |   var count = 2;\n  \
+             |       ^^^^^ declared again here\n"
+        );
+    }
+    {
+        // Runtime errors list the stack trace as a note.
+        let source = "fun f() {\n\treturn 1 + nil;\n}\nf();";
+        let mut program = crate::Parser::new(source).statements().unwrap();
+        let heap = crate::gc::Heap::new();
+        let function = crate::compiler::compile(&mut program, &heap).unwrap();
+        let mut vm = crate::vm::VM::new(function, heap);
+        let error = vm.interpret().unwrap_err();
+        let diagnostic = Diagnostic::from(&error);
+        assert_eq!(diagnostic.primary, Some(Label::new(2, 11, "")));
+        let rendered = diagnostic.render(source, "test.lox", false);
+        let mut lines = rendered.lines().skip(3);
+        assert_eq!(lines.next(), Some("2 | \treturn 1 + nil;"));
+        assert_eq!(lines.next(), Some("  | \t         ^"));
+        assert_eq!(
+            lines.next(),
+            Some("  = note: stack trace, innermost call first:")
+        );
+        assert_eq!(lines.next(), Some("          [line 2] in f()"));
+        assert_eq!(lines.next(), Some("          [line 4] in script"));
+
+        let coloured = diagnostic.render(source, "test.lox", true);
+        assert!(coloured.starts_with("\x1b[1;31merror[R0003]\x1b[0m"));
+    }
+    {
+        let source = "{\n  var a = 1;\n  var a = 2;\n}";
+        let mut program = crate::Parser::new(source).statements().unwrap();
+        let heap = crate::gc::Heap::new();
+        let error = crate::compiler::compile(&mut program, &heap).err().unwrap();
+        assert_eq!(
+            Diagnostic::from(&error).to_json(source, "dir\\\"test\".lox"),
+            concat!(
+                r#"{"severity":"error","code":"C0002","#,
+                r#""message":"Variable 'a' is already declared in this scope","#,
+                r#""file":"dir\\\"test\".lox","#,
+                r#""start_line":3,"start_column":7,"end_line":3,"end_column":8,"#,
+                r#""labels":[{"message":"declared again here","#,
+                r#""start_line":3,"start_column":7,"end_line":3,"end_column":8,"primary":true},"#,
+                r#"{"message":"first declared here","#,
+                r#""start_line":2,"start_column":7,"end_line":2,"end_column":8,"primary":false}],"#,
+                r#""notes":[]}"#
+            )
+        );
+
+        // Columns count characters, and spans end after the whole token.
+        let source = "print \"caf\u{e9}\" +;\nprint 1 \"two\nlines\";";
+        let errors = crate::Parser::new(source).statements().err().unwrap();
+        let json = Diagnostic::from(&errors[0]).to_json(source, "test.lox");
+        assert!(json.contains(r#""start_line":1,"start_column":15,"end_line":1,"end_column":16,"#));
+        let rendered = Diagnostic::from(&errors[0]).render(source, "test.lox", false);
+        assert!(rendered.contains("--> test.lox:1:15\n"));
+        assert!(rendered.ends_with("  |               ^ expected an expression\n"));
+        let json = Diagnostic::from(&errors[1]).to_json(source, "test.lox");
+        assert!(json.contains(r#""start_line":2,"start_column":9,"end_line":3,"end_column":7,"#));
+        let rendered = Diagnostic::from(&errors[1]).render(source, "test.lox", false);
+        assert!(rendered.ends_with("  |         ^^^^ expected Semicolon\n"));
+
+        let diagnostic = Diagnostic::from(&Error::stack_overflow());
+        assert!(diagnostic.to_json("", "test.lox").contains(
+            r#""start_line":null,"start_column":null,"end_line":null,"end_column":null,"labels":[]"#
+        ));
+    }
+    {
+        // Errors without a location still render.
+        let rendered = Diagnostic::from(&Error::stack_overflow()).render("", "test.lox", false);
+        assert_eq!(rendered, "error[R0008]: Stack overflow\n--> test.lox\n");
+    }
+    {
+        // Chunk limits point at the code that outgrew them.
+        let source = format!("var x;\nwhile (x)\n{{{}}}", "print 1;".repeat(22_000));
+        let mut program = crate::Parser::new(&source).statements().unwrap();
+        let heap = crate::gc::Heap::new();
+        let error = crate::compiler::compile(&mut program, &heap).err().unwrap();
+        let rendered = Diagnostic::from(&error).render(&source, "test.lox", false);
+        let mut lines = rendered.lines();
+        assert_eq!(
+            lines.next(),
+            Some("error[C0005]: Too much code to jump over")
+        );
+        assert_eq!(lines.next(), Some(" --> test.lox:2:8"));
+        assert_eq!(lines.nth(1), Some("2 | while (x)"));
+        assert_eq!(lines.next(), Some("  |        ^"));
+
+        let error = Error::too_many_constants(1, 7);
+        let rendered = Diagnostic::from(&error).render("print \"s\";", "test.lox", false);
+        assert_eq!(
+            rendered,
+            "error[C0006]: Too many constants in one chunk\n \
+             --> test.lox:1:7\n  \
+             |\n\
+             1 | print \"s\";\n  \
+             |       ^^^\n"
+        );
+    }
+}
@@ -0,0 +1,235 @@
+use crate::Value;
+use crate::diagnostic::json_string;
+use crate::object::Function;
+use std::collections::HashSet;
+use std::fmt::Write;
+
+/// The script followed by every function nested in it, each before the
+/// functions and methods declared inside it.
+pub fn functions(script: &Function) -> Vec<&Function> {
+    let mut functions = vec![script];
+    let mut i = 0;
+    while i < functions.len() {
+        let nested: Vec<_> = functions[i]
+            .chunk
+            .constants
+            .iter()
+            .filter_map(|constant| match constant {
+                Value::Function(function) => Some(&**function),
+                _ => None,
+            })
+            .collect();
+        // Insert right after the parent, so the order is depth first.
+        functions.splice(i + 1..i + 1, nested);
+        i += 1;
+    }
+    functions
+}
+
+/// Describes a constant with its type, quoting strings.
+fn describe_constant(constant: &Value) -> (&'static str, String) {
+    match constant {
+        Value::String(s) => ("string", format!("{:?}", s.as_str())),
+        constant => (constant.type_name(), constant.to_string()),
+    }
+}
+
+/// Offsets that some jump in the chunk lands on.
+fn jump_targets(function: &Function) -> HashSet<usize> {
+    let chunk = &function.chunk;
+    let mut targets = HashSet::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        targets.extend(chunk.jump_target(offset));
+        let mut sink = String::new();
+        match chunk.dissasemble_instruction(&mut sink, offset) {
+            Ok(len) => offset += len,
+            Err(_) => break,
+        }
+    }
+    targets
+}
+
+/// Lists the constant pool, line table and code of every chunk in the program.
+///
+/// Offsets are printed from 1, as in `Chunk`'s `Debug` output, and
+/// instructions that a jump lands on are marked with `>`.
+pub fn render(script: &Function) -> String {
+    let mut out = String::new();
+    for function in functions(script) {
+        let chunk = &function.chunk;
+        writeln!(out, "== {function} ==").unwrap();
+
+        writeln!(out, "constants:").unwrap();
+        for (id, constant) in chunk.constants.iter().enumerate() {
+            let (kind, value) = describe_constant(constant);
+            writeln!(out, "  {id:4} {kind:<8} {value}").unwrap();
+        }
+
+        writeln!(out, "lines:").unwrap();
+        let mut start = 0;
+        for run in &chunk.lines {
+            let end = start + run.count;
+            writeln!(
+                out,
+                "  {:04}-{:04} {}:{}",
+                start + 1,
+                end,
+                run.line,
+                run.column
+            )
+            .unwrap();
+            start = end;
+        }
+
+        writeln!(out, "code:").unwrap();
+        let targets = jump_targets(function);
+        let mut offset = 0;
+        let mut previous_line = None;
+        while offset < chunk.code.len() {
+            let marker = if targets.contains(&offset) { '>' } else { ' ' };
+            let line = chunk.position(offset).map(|(line, _)| line);
+            match line {
+                Some(line) if previous_line != Some(line) => {
+                    write!(out, "{marker} {:04} {line:4} ", offset + 1).unwrap()
+                }
+                _ => write!(out, "{marker} {:04}    | ", offset + 1).unwrap(),
+            }
+            previous_line = line;
+            let mut text = String::new();
+            let Ok(len) = chunk.dissasemble_instruction(&mut text, offset) else {
+                writeln!(out, "<invalid instruction {:#x}>", chunk.code[offset]).unwrap();
+                break;
+            };
+            let mut lines = text.lines();
+            writeln!(out, "{}", lines.next().unwrap_or_default()).unwrap();
+            for line in lines {
+                writeln!(out, "  {line}").unwrap();
+            }
+            offset += len;
+        }
+        writeln!(out).unwrap();
+    }
+    out
+}
+
+/// Like `render`, but as one JSON document. Offsets here are byte indices
+/// into the chunk's code, counting from 0.
+pub fn to_json(script: &Function) -> String {
+    let chunks: Vec<String> = functions(script)
+        .into_iter()
+        .map(|function| {
+            let chunk = &function.chunk;
+            let constants: Vec<String> = chunk
+                .constants
+                .iter()
+                .map(|constant| {
+                    let (kind, value) = describe_constant(constant);
+                    let value = match constant {
+                        Value::Nil => "null".into(),
+                        Value::Bool(b) => b.to_string(),
+                        // JSON has no infinities or NaN, those stay strings.
+                        Value::Number(x) if x.is_finite() => x.to_string(),
+                        Value::String(s) => json_string(s),
+                        _ => json_string(&value),
+                    };
+                    format!(r#"{{"type":"{kind}","value":{value}}}"#)
+                })
+                .collect();
+
+            let mut start = 0;
+            let lines: Vec<String> = chunk
+                .lines
+                .iter()
+                .map(|run| {
+                    let json = format!(
+                        r#"{{"start":{start},"end":{},"line":{},"column":{}}}"#,
+                        start + run.count,
+                        run.line,
+                        run.column
+                    );
+                    start += run.count;
+                    json
+                })
+                .collect();
+
+            let targets = jump_targets(function);
+            let mut code = Vec::new();
+            let mut offset = 0;
+            while offset < chunk.code.len() {
+                let mut text = String::new();
+                let Ok(len) = chunk.dissasemble_instruction(&mut text, offset) else {
+                    break;
+                };
+                let operands: Vec<String> = chunk.code[offset + 1..offset + len]
+                    .iter()
+                    .map(u8::to_string)
+                    .collect();
+                let (line, column) = chunk.position(offset).unwrap_or_default();
+                let target = chunk
+                    .jump_target(offset)
+                    .map_or("null".to_string(), |target| target.to_string());
+                code.push(format!(
+                    r#"{{"offset":{offset},"line":{line},"column":{column},"text":{},"operands":[{}],"jump_target":{target},"is_jump_target":{}}}"#,
+                    json_string(text.trim_end()),
+                    operands.join(","),
+                    targets.contains(&offset)
+                ));
+                offset += len;
+            }
+
+            format!(
+                r#"{{"name":{},"arity":{},"constants":[{}],"lines":[{}],"code":[{}]}}"#,
+                json_string(&function.to_string()),
+                function.arity,
+                constants.join(","),
+                lines.join(","),
+                code.join(",")
+            )
+        })
+        .collect();
+    format!(r#"{{"chunks":[{}]}}"#, chunks.join(","))
+}
+
+#[test]
+fn tests() {
+    use crate::gc::Heap;
+
+    let input = "
+        fun outer() {
+          fun inner() {}
+          return inner;
+        }
+        class A {
+          m() { var x = 1.5; return x == nil == true; }
+        }
+        var i = 0;
+        while (i < 2) i = i + 1;
+    ";
+    let mut program = crate::Parser::new(input).statements().unwrap();
+    let heap = Heap::new();
+    let script = crate::compiler::compile(&mut program, &heap).unwrap();
+    let names: Vec<String> = functions(&script).iter().map(|f| f.to_string()).collect();
+    assert_eq!(names, ["<script>", "<fn outer>", "<fn inner>", "<fn m>"]);
+
+    let text = render(&script);
+    assert!(text.starts_with("== <script> ==\nconstants:\n     0 function <fn outer>\n"));
+    assert!(text.contains("     1 string   \"outer\"\n"));
+    assert!(text.contains("lines:\n  0001-0004 2:13\n"));
+    // The loop jumps back to the condition, which is marked as a target.
+    let condition = text
+        .lines()
+        .find(|line| line.contains("GetGlobal") && line.starts_with('>'))
+        .expect("the loop condition is a jump target");
+    let loop_line = text.lines().find(|line| line.contains("Loop")).unwrap();
+    assert!(loop_line.ends_with(&format!("-> {}", &condition[2..6])));
+    assert!(text.contains("== <fn inner> ==\n"));
+
+    let json = to_json(&script);
+    assert!(json.starts_with(r#"{"chunks":[{"name":"<script>","arity":0,"constants":[{"type":"function","value":"<fn outer>"},{"type":"string","value":"outer"}"#));
+    assert!(json.contains(r#"{"start":0,"end":4,"line":2,"column":13}"#));
+    assert!(json.contains(r#""is_jump_target":true"#));
+    assert!(json.contains(
+        r#"{"name":"<fn m>","arity":0,"constants":[{"type":"number","value":1.5},{"type":"nil","value":null},{"type":"bool","value":true}]"#
+    ));
+}
@@ -1,6 +1,25 @@
+use crate::ast::{Identifier, Position};
 use crate::lex::{Token, TokenKind};
+use crate::object::Function;
 use std::fmt;
 
+/// One active call at the time of a runtime error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFrame {
+    /// `None` for the top-level script.
+    pub function: Option<String>,
+    pub line: usize,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {name}()", self.line),
+            None => write!(f, "[line {}] in script", self.line),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     // Lexer errors
@@ -25,6 +44,74 @@ pub enum Error {
         line: usize,
         column: usize,
     },
+    InvalidAssignmentTarget {
+        line: usize,
+        column: usize,
+    },
+    TooManyArguments {
+        line: usize,
+        column: usize,
+    },
+    TooManyParameters {
+        line: usize,
+        column: usize,
+    },
+    // Compiler errors
+    VariableInOwnInitializer {
+        name: String,
+        line: usize,
+        column: usize,
+        declared_line: usize,
+        declared_column: usize,
+    },
+    DuplicateVariable {
+        name: String,
+        line: usize,
+        column: usize,
+        declared_line: usize,
+        declared_column: usize,
+    },
+    TooManyLocals {
+        line: usize,
+        column: usize,
+    },
+    TooManyUpvalues {
+        line: usize,
+        column: usize,
+    },
+    JumpTooLarge {
+        line: usize,
+        column: usize,
+    },
+    TooManyConstants {
+        line: usize,
+        column: usize,
+    },
+    ReturnAtTopLevel {
+        line: usize,
+        column: usize,
+    },
+    ReturnValueFromInitializer {
+        line: usize,
+        column: usize,
+    },
+    ThisOutsideClass {
+        line: usize,
+        column: usize,
+    },
+    SuperOutsideClass {
+        line: usize,
+        column: usize,
+    },
+    SuperWithoutSuperclass {
+        line: usize,
+        column: usize,
+    },
+    ClassInheritsFromItself {
+        name: String,
+        line: usize,
+        column: usize,
+    },
     // Runtime errors
     InvalidInstruction {
         opcode: u8,
@@ -37,8 +124,48 @@ pub enum Error {
         operation: String,
         type_name: String,
     },
-    DivisionByZero {
+    DivisionByZero,
+    UndefinedVariable {
+        name: String,
+    },
+    UndefinedProperty {
+        name: String,
+    },
+    ArityMismatch {
+        expected: usize,
+        found: usize,
+    },
+    StackOverflow,
+    NativeError {
+        name: String,
+        message: String,
+    },
+    // Bytecode file errors
+    NotBytecode,
+    BytecodeVersionMismatch {
+        found: u16,
+        expected: u16,
+    },
+    ChecksumMismatch {
+        expected: u32,
+        found: u32,
+    },
+    CorruptBytecode {
+        offset: usize,
+        reason: String,
+    },
+    InvalidBytecode {
+        function: String,
+        offset: usize,
+        reason: String,
+    },
+    /// A runtime error together with where the faulting instruction came from
+    /// and the calls that led there, innermost first.
+    Runtime {
         line: usize,
+        column: usize,
+        error: Box<Error>,
+        trace: Vec<TraceFrame>,
     },
 }
 
@@ -68,6 +195,106 @@ impl Error {
         }
     }
 
+    pub fn invalid_assignment_target(token: &Token<'_>) -> Self {
+        Self::InvalidAssignmentTarget {
+            line: token.line,
+            column: token.column,
+        }
+    }
+
+    pub fn too_many_arguments(token: &Token<'_>) -> Self {
+        Self::TooManyArguments {
+            line: token.line,
+            column: token.column,
+        }
+    }
+
+    pub fn too_many_parameters(token: &Token<'_>) -> Self {
+        Self::TooManyParameters {
+            line: token.line,
+            column: token.column,
+        }
+    }
+
+    pub fn variable_in_own_initializer(name: &Identifier, declared: Position) -> Self {
+        Self::VariableInOwnInitializer {
+            name: name.name.clone(),
+            line: name.line,
+            column: name.column,
+            declared_line: declared.line,
+            declared_column: declared.column,
+        }
+    }
+
+    pub fn duplicate_variable(name: &Identifier, declared: Position) -> Self {
+        Self::DuplicateVariable {
+            name: name.name.clone(),
+            line: name.line,
+            column: name.column,
+            declared_line: declared.line,
+            declared_column: declared.column,
+        }
+    }
+
+    pub fn too_many_locals(name: &Identifier) -> Self {
+        Self::TooManyLocals {
+            line: name.line,
+            column: name.column,
+        }
+    }
+
+    pub fn too_many_upvalues(name: &Identifier) -> Self {
+        Self::TooManyUpvalues {
+            line: name.line,
+            column: name.column,
+        }
+    }
+
+    pub fn jump_too_large(line: usize, column: usize) -> Self {
+        Self::JumpTooLarge { line, column }
+    }
+
+    pub fn too_many_constants(line: usize, column: usize) -> Self {
+        Self::TooManyConstants { line, column }
+    }
+
+    pub fn return_at_top_level(line: usize, column: usize) -> Self {
+        Self::ReturnAtTopLevel { line, column }
+    }
+
+    pub fn return_value_from_initializer(line: usize, column: usize) -> Self {
+        Self::ReturnValueFromInitializer { line, column }
+    }
+
+    pub fn this_outside_class(this: &Identifier) -> Self {
+        Self::ThisOutsideClass {
+            line: this.line,
+            column: this.column,
+        }
+    }
+
+    pub fn super_outside_class(keyword: &Identifier) -> Self {
+        Self::SuperOutsideClass {
+            line: keyword.line,
+            column: keyword.column,
+        }
+    }
+
+    pub fn super_without_superclass(keyword: &Identifier) -> Self {
+        Self::SuperWithoutSuperclass {
+            line: keyword.line,
+            column: keyword.column,
+        }
+    }
+
+    pub fn class_inherits_from_itself(superclass: &Identifier) -> Self {
+        Self::ClassInheritsFromItself {
+            name: superclass.name.clone(),
+            line: superclass.line,
+            column: superclass.column,
+        }
+    }
+
     pub fn invalid_instruction(opcode: u8, offset: usize) -> Self {
         Self::InvalidInstruction { opcode, offset }
     }
@@ -85,55 +312,241 @@ impl Error {
         }
     }
 
-    pub fn division_by_zero(line: usize) -> Self {
-        Self::DivisionByZero { line }
+    pub fn division_by_zero() -> Self {
+        Self::DivisionByZero
+    }
+
+    pub fn undefined_variable(name: impl Into<String>) -> Self {
+        Self::UndefinedVariable { name: name.into() }
+    }
+
+    pub fn undefined_property(name: impl Into<String>) -> Self {
+        Self::UndefinedProperty { name: name.into() }
+    }
+
+    pub fn arity_mismatch(expected: usize, found: usize) -> Self {
+        Self::ArityMismatch { expected, found }
+    }
+
+    pub fn stack_overflow() -> Self {
+        Self::StackOverflow
+    }
+
+    pub fn native_error(name: impl Into<String>, message: impl Into<String>) -> Self {
+        Self::NativeError {
+            name: name.into(),
+            message: message.into(),
+        }
+    }
+
+    pub fn not_bytecode() -> Self {
+        Self::NotBytecode
+    }
+
+    pub fn bytecode_version_mismatch(found: u16, expected: u16) -> Self {
+        Self::BytecodeVersionMismatch { found, expected }
+    }
+
+    pub fn checksum_mismatch(expected: u32, found: u32) -> Self {
+        Self::ChecksumMismatch { expected, found }
+    }
+
+    pub fn corrupt_bytecode(offset: usize, reason: impl Into<String>) -> Self {
+        Self::CorruptBytecode {
+            offset,
+            reason: reason.into(),
+        }
+    }
+
+    pub fn invalid_bytecode(function: &Function, offset: usize, reason: impl Into<String>) -> Self {
+        Self::InvalidBytecode {
+            function: function.to_string(),
+            offset,
+            reason: reason.into(),
+        }
+    }
+
+    pub fn runtime(line: usize, column: usize, error: Error, trace: Vec<TraceFrame>) -> Self {
+        Self::Runtime {
+            line,
+            column,
+            error: Box::new(error),
+            trace,
+        }
     }
 }
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl Error {
+    /// Where in the source the error happened, if it is tied to one place.
+    pub fn location(&self) -> Option<(usize, usize)> {
         match self {
-            Error::UnexpectedChar { ch, line, column } => {
-                write!(f, "[{line}:{column}] Unexpected character '{ch}'")
-            }
-            Error::UnterminatedString { line, column } => {
-                write!(f, "[{line}:{column}] Unterminated string literal")
-            }
-            Error::InvalidNumber {
-                lexeme,
-                line,
-                column,
-            } => {
-                write!(f, "[{line}:{column}] Invalid number: '{lexeme}'")
-            }
+            Error::UnexpectedChar { line, column, .. }
+            | Error::UnterminatedString { line, column }
+            | Error::InvalidNumber { line, column, .. }
+            | Error::UnexpectedToken { line, column, .. }
+            | Error::InvalidAssignmentTarget { line, column }
+            | Error::TooManyArguments { line, column }
+            | Error::TooManyParameters { line, column }
+            | Error::VariableInOwnInitializer { line, column, .. }
+            | Error::DuplicateVariable { line, column, .. }
+            | Error::TooManyLocals { line, column }
+            | Error::TooManyUpvalues { line, column }
+            | Error::JumpTooLarge { line, column }
+            | Error::TooManyConstants { line, column }
+            | Error::ReturnAtTopLevel { line, column }
+            | Error::ReturnValueFromInitializer { line, column }
+            | Error::ThisOutsideClass { line, column }
+            | Error::SuperOutsideClass { line, column }
+            | Error::SuperWithoutSuperclass { line, column }
+            | Error::ClassInheritsFromItself { line, column, .. }
+            | Error::Runtime { line, column, .. } => Some((*line, *column)),
+            Error::InvalidInstruction { .. }
+            | Error::StackUnderflow { .. }
+            | Error::TypeError { .. }
+            | Error::DivisionByZero
+            | Error::UndefinedVariable { .. }
+            | Error::UndefinedProperty { .. }
+            | Error::ArityMismatch { .. }
+            | Error::StackOverflow
+            | Error::NativeError { .. }
+            | Error::NotBytecode
+            | Error::BytecodeVersionMismatch { .. }
+            | Error::ChecksumMismatch { .. }
+            | Error::CorruptBytecode { .. }
+            | Error::InvalidBytecode { .. } => None,
+        }
+    }
+
+    /// A stable identifier for the kind of error, documented by `loxemu explain`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::UnexpectedChar { .. } => "L0001",
+            Error::UnterminatedString { .. } => "L0002",
+            Error::InvalidNumber { .. } => "P0001",
+            Error::UnexpectedToken { .. } => "P0002",
+            Error::InvalidAssignmentTarget { .. } => "P0003",
+            Error::TooManyArguments { .. } => "P0004",
+            Error::TooManyParameters { .. } => "P0005",
+            Error::VariableInOwnInitializer { .. } => "C0001",
+            Error::DuplicateVariable { .. } => "C0002",
+            Error::TooManyLocals { .. } => "C0003",
+            Error::TooManyUpvalues { .. } => "C0004",
+            Error::JumpTooLarge { .. } => "C0005",
+            Error::TooManyConstants { .. } => "C0006",
+            Error::ReturnAtTopLevel { .. } => "C0007",
+            Error::ReturnValueFromInitializer { .. } => "C0008",
+            Error::ThisOutsideClass { .. } => "C0009",
+            Error::SuperOutsideClass { .. } => "C0010",
+            Error::SuperWithoutSuperclass { .. } => "C0011",
+            Error::ClassInheritsFromItself { .. } => "C0012",
+            Error::InvalidInstruction { .. } => "R0001",
+            Error::StackUnderflow { .. } => "R0002",
+            Error::TypeError { .. } => "R0003",
+            Error::DivisionByZero => "R0004",
+            Error::UndefinedVariable { .. } => "R0005",
+            Error::UndefinedProperty { .. } => "R0006",
+            Error::ArityMismatch { .. } => "R0007",
+            Error::StackOverflow => "R0008",
+            Error::NativeError { .. } => "R0009",
+            Error::NotBytecode => "B0001",
+            Error::BytecodeVersionMismatch { .. } => "B0002",
+            Error::ChecksumMismatch { .. } => "B0003",
+            Error::CorruptBytecode { .. } => "B0004",
+            Error::InvalidBytecode { .. } => "B0005",
+            Error::Runtime { error, .. } => error.code(),
+        }
+    }
+
+    /// The error message without its location.
+    pub fn message(&self) -> String {
+        match self {
+            Error::UnexpectedChar { ch, .. } => format!("Unexpected character '{ch}'"),
+            Error::UnterminatedString { .. } => "Unterminated string literal".into(),
+            Error::InvalidNumber { lexeme, .. } => format!("Invalid number: '{lexeme}'"),
             Error::UnexpectedToken {
-                expected,
-                found,
-                line,
-                column,
+                expected, found, ..
             } => {
                 if let Some(exp) = expected {
-                    write!(f, "[{line}:{column}] Expected {exp:?}, found {found:?}")
+                    format!("Expected {exp:?}, found {found:?}")
                 } else {
-                    write!(f, "[{line}:{column}] Unexpected token: {found:?}")
+                    format!("Unexpected token: {found:?}")
                 }
             }
-            Error::InvalidInstruction { opcode, offset } => {
-                write!(f, "Invalid instruction {opcode:#x} at offset {offset}")
+            Error::InvalidAssignmentTarget { .. } => "Invalid assignment target".into(),
+            Error::TooManyArguments { .. } => "Can't have more than 255 arguments".into(),
+            Error::TooManyParameters { .. } => "Can't have more than 255 parameters".into(),
+            Error::VariableInOwnInitializer { name, .. } => {
+                format!("Can't read local variable '{name}' in its own initializer")
             }
-            Error::StackUnderflow { operation } => {
-                write!(f, "Stack underflow during {operation}")
+            Error::DuplicateVariable { name, .. } => {
+                format!("Variable '{name}' is already declared in this scope")
+            }
+            Error::TooManyLocals { .. } => "Too many local variables in scope".into(),
+            Error::TooManyUpvalues { .. } => "Too many closure variables in function".into(),
+            Error::JumpTooLarge { .. } => "Too much code to jump over".into(),
+            Error::TooManyConstants { .. } => "Too many constants in one chunk".into(),
+            Error::ReturnAtTopLevel { .. } => "Can't return from top-level code".into(),
+            Error::ReturnValueFromInitializer { .. } => {
+                "Can't return a value from an initializer".into()
+            }
+            Error::ThisOutsideClass { .. } => "Can't use 'this' outside of a class".into(),
+            Error::SuperOutsideClass { .. } => "Can't use 'super' outside of a class".into(),
+            Error::SuperWithoutSuperclass { .. } => {
+                "Can't use 'super' in a class with no superclass".into()
+            }
+            Error::ClassInheritsFromItself { name, .. } => {
+                format!("Class '{name}' can't inherit from itself")
+            }
+            Error::InvalidInstruction { opcode, offset } => {
+                format!("Invalid instruction {opcode:#x} at offset {offset}")
             }
+            Error::StackUnderflow { operation } => format!("Stack underflow during {operation}"),
             Error::TypeError {
                 operation,
                 type_name,
-            } => {
-                write!(f, "Type error: cannot {operation} on {type_name}")
+            } => format!("Type error: cannot {operation} on {type_name}"),
+            Error::DivisionByZero => "Division by zero".into(),
+            Error::UndefinedVariable { name } => format!("Undefined variable '{name}'"),
+            Error::UndefinedProperty { name } => format!("Undefined property '{name}'"),
+            Error::ArityMismatch { expected, found } => {
+                format!("Expected {expected} arguments but got {found}")
+            }
+            Error::StackOverflow => "Stack overflow".into(),
+            Error::NativeError { name, message } => {
+                format!("Error in native function '{name}': {message}")
+            }
+            Error::NotBytecode => "Not a Lox bytecode file".into(),
+            Error::BytecodeVersionMismatch { found, expected } => format!(
+                "Bytecode format version {found} is not supported, expected version {expected}"
+            ),
+            Error::ChecksumMismatch { expected, found } => format!(
+                "Bytecode file is corrupt: checksum is {found:#010x}, expected {expected:#010x}"
+            ),
+            Error::CorruptBytecode { offset, reason } => {
+                format!("Bytecode file is corrupt at byte {offset}: {reason}")
             }
-            Error::DivisionByZero { line } => {
-                write!(f, "[{line}] Division by zero")
+            Error::InvalidBytecode {
+                function,
+                offset,
+                reason,
+            } => format!("Invalid bytecode in {function} at offset {offset}: {reason}"),
+            Error::Runtime { error, .. } => error.message(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some((line, column)) = self.location() {
+            write!(f, "[{line}:{column}] ")?;
+        }
+        write!(f, "error[{}]: {}", self.code(), self.message())?;
+        if let Error::Runtime { trace, .. } = self {
+            for frame in trace {
+                write!(f, "\n{frame}")?;
             }
         }
+        Ok(())
     }
 }
 
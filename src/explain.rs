@@ -0,0 +1,393 @@
+/// Long-form explanations of the codes returned by `Error::code`, each with
+/// an example and how to fix it.
+const EXPLANATIONS: &[(&str, &str)] = &[
+    (
+        "L0001",
+        r#"A character that is not part of Lox appeared outside a string or comment.
+
+Example:
+
+    var price = 10 @ 2;
+
+Lox has no `@` operator. Remove the character, or put it inside a string
+literal if it was meant as text."#,
+    ),
+    (
+        "L0002",
+        r#"A string literal is missing its closing quote.
+
+Example:
+
+    print "hello;
+
+Everything after the opening `"` up to the end of the file was read as part
+of the string. Add the closing quote: `print "hello";`."#,
+    ),
+    (
+        "P0001",
+        r#"A number literal could not be read as a number.
+
+Example:
+
+    print 1.2.3;
+
+Number literals are digits with at most one decimal point. Write a single
+number, such as `1.23`."#,
+    ),
+    (
+        "P0002",
+        r#"The parser found a token it did not expect at this point.
+
+Example:
+
+    var x = 1
+    print x;
+
+The error names what was expected, here the `;` ending the declaration.
+Add the missing token or remove the stray one: `var x = 1;`."#,
+    ),
+    (
+        "P0003",
+        r#"The left-hand side of `=` is not something that can be assigned to.
+
+Example:
+
+    a + b = 1;
+
+Only variables and properties can be assigned. Assign to a variable
+instead: `a = 1 - b;`."#,
+    ),
+    (
+        "P0004",
+        r#"A call passes more than 255 arguments.
+
+Example:
+
+    f(a1, a2, /* ... */ a256);
+
+Calls are limited to 255 arguments. Group related values into an instance
+and pass that instead."#,
+    ),
+    (
+        "P0005",
+        r#"A function declares more than 255 parameters.
+
+Example:
+
+    fun f(a1, a2, /* ... */ a256) {}
+
+Functions are limited to 255 parameters. Group related values into an
+instance and take that instead."#,
+    ),
+    (
+        "C0001",
+        r#"A local variable is read in its own initializer.
+
+Example:
+
+    var a = 1;
+    {
+      var a = a + 1;
+    }
+
+The inner `a` is not defined until its initializer finishes, so it cannot
+refer to itself, not even to reach the outer `a`. Use a different name:
+`var b = a + 1;`."#,
+    ),
+    (
+        "C0002",
+        r#"A local variable is declared twice in the same scope.
+
+Example:
+
+    {
+      var count = 1;
+      var count = 2;
+    }
+
+Assign to the existing variable instead, `count = 2;`, or give the second
+variable a different name. Shadowing is allowed in a nested block."#,
+    ),
+    (
+        "C0003",
+        r#"A function has more than 256 local variables in scope at once.
+
+Locals live in one-byte stack slots. Split the function into smaller
+functions, or move variables into nested blocks that end sooner."#,
+    ),
+    (
+        "C0004",
+        r#"A closure captures more than 256 variables from enclosing functions.
+
+Captured variables are numbered with one byte. Capture fewer variables,
+for example by grouping them into an instance and capturing that."#,
+    ),
+    (
+        "C0005",
+        r#"A jump in the compiled code is longer than 65535 bytes.
+
+The body of an `if`, `while`, `for`, `and` or `or` compiled to too much
+code to jump over. Move parts of the body into functions."#,
+    ),
+    (
+        "C0006",
+        r#"A chunk needs more constants than it can address.
+
+Constants, including the names of global variables, properties and
+methods, are addressed with up to three bytes, so a function can have at
+most 16777216 of them. Split the function, or the top-level script, into
+smaller functions."#,
+    ),
+    (
+        "C0007",
+        r#"A `return` statement appears outside of any function.
+
+Example:
+
+    return 1;
+
+Only functions can return. Remove the statement, or move the code into a
+function."#,
+    ),
+    (
+        "C0008",
+        r#"An initializer returns a value.
+
+Example:
+
+    class Point {
+      init(x) {
+        this.x = x;
+        return x;
+      }
+    }
+
+Calling a class always produces the new instance, so `init` cannot return
+anything else. Use a bare `return;` to leave early."#,
+    ),
+    (
+        "C0009",
+        r#"`this` is used outside of a class.
+
+Example:
+
+    fun show() {
+      print this;
+    }
+
+`this` only refers to something inside a method. Pass the object as a
+parameter instead: `fun show(object) { print object; }`."#,
+    ),
+    (
+        "C0010",
+        r#"`super` is used outside of a class.
+
+Example:
+
+    super.init();
+
+`super` only makes sense inside the methods of a subclass. Call the
+method on an instance instead."#,
+    ),
+    (
+        "C0011",
+        r#"`super` is used in a class that has no superclass.
+
+Example:
+
+    class Dog {
+      speak() { super.speak(); }
+    }
+
+Declare the superclass, `class Dog < Animal { ... }`, or call a method of
+the class itself with `this`."#,
+    ),
+    (
+        "C0012",
+        r#"A class names itself as its superclass.
+
+Example:
+
+    class Node < Node {}
+
+A class cannot inherit from itself. Name a different class, or drop the
+`< Node` clause."#,
+    ),
+    (
+        "R0001",
+        r#"The VM met a byte that is not a valid instruction.
+
+This means the bytecode is corrupt or was not produced by the compiler.
+Recompile the program from source."#,
+    ),
+    (
+        "R0002",
+        r#"An instruction needed more values than the VM stack held.
+
+This means the bytecode is corrupt or was not produced by the compiler.
+Recompile the program from source."#,
+    ),
+    (
+        "R0003",
+        r#"An operation was applied to values of the wrong type.
+
+Example:
+
+    print 1 + "one";
+
+`+` adds two numbers or joins two strings, but cannot mix them. Arithmetic
+and comparisons need numbers, and only functions and classes can be
+called. Convert the operands or check the types before the operation."#,
+    ),
+    (
+        "R0004",
+        r#"A number was divided by zero.
+
+Example:
+
+    var count = 0;
+    print 10 / count;
+
+Lox reports division by zero, including `0 / 0`, instead of producing
+infinity or NaN. Check the divisor before dividing:
+`if (count != 0) print 10 / count;`."#,
+    ),
+    (
+        "R0005",
+        r#"A global variable is read or assigned before it is defined.
+
+Example:
+
+    print total;
+    var total = 0;
+
+Define the variable with `var` before using it, and check the name for
+typos. Assignment does not create a global: use `var total = 1;`, not
+`total = 1;`."#,
+    ),
+    (
+        "R0006",
+        r#"An instance has no field or method with the given name.
+
+Example:
+
+    class Point {}
+    print Point().x;
+
+Set the field before reading it, for example in `init`, and check the
+name for typos."#,
+    ),
+    (
+        "R0007",
+        r#"A function or class was called with the wrong number of arguments.
+
+Example:
+
+    fun add(a, b) { return a + b; }
+    add(1);
+
+Pass exactly as many arguments as the function, or the class's `init`,
+has parameters: `add(1, 2);`."#,
+    ),
+    (
+        "R0008",
+        r#"Calls nested too deeply.
+
+Example:
+
+    fun forever() { forever(); }
+    forever();
+
+This is almost always recursion without a base case. Make sure every
+recursive function stops, or turn deep recursion into a loop."#,
+    ),
+    (
+        "R0009",
+        r#"A built-in function reported an error.
+
+The message says which native function failed and why. Check the
+arguments passed to it."#,
+    ),
+    (
+        "B0001",
+        r#"A file given to `loxemu exec` is not a compiled Lox program.
+
+Example:
+
+    loxemu exec hello.lox
+
+`exec` runs the `.loxc` files written by `loxemu compile`. Run source files
+with `loxemu run`, or compile them first: `loxemu compile hello.lox`."#,
+    ),
+    (
+        "B0002",
+        r#"A `.loxc` file was written by a release of loxemu with a different
+bytecode format.
+
+Bytecode is only read by the release that wrote it. Recompile the program
+from source with this release: `loxemu compile hello.lox`."#,
+    ),
+    (
+        "B0003",
+        r#"The checksum stored in a `.loxc` file does not match its contents.
+
+The file was changed or damaged after it was compiled, for example by an
+incomplete download or a text-mode transfer. Recompile it from source."#,
+    ),
+    (
+        "B0004",
+        r#"A `.loxc` file has a valid checksum but its contents do not make sense.
+
+The message gives the byte offset where reading failed. The file was not
+written by `loxemu compile`, or was written by a buggy tool. Recompile it
+from source."#,
+    ),
+    (
+        "B0005",
+        r#"A `.loxc` file holds bytecode that could crash or confuse the VM.
+
+Before running a bytecode file, `loxemu exec` checks that every instruction
+is valid, that its operands refer to existing constants, variables and
+instructions, and that the stack never underflows. The message names the
+function and the offset of the offending instruction; `loxemu disasm` on
+the original source shows what the compiler would have produced. Recompile
+the program from source."#,
+    ),
+];
+
+/// The explanation for an error code, ignoring case.
+pub fn explain(code: &str) -> Option<&'static str> {
+    EXPLANATIONS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(code))
+        .map(|(_, explanation)| *explanation)
+}
+
+#[test]
+fn tests() {
+    use crate::error::Error;
+
+    assert!(explain("R0003").unwrap().contains("1 + \"one\""));
+    assert_eq!(explain("r0003"), explain("R0003"));
+    assert_eq!(explain("X9999"), None);
+
+    let codes: Vec<_> = EXPLANATIONS.iter().map(|(code, _)| *code).collect();
+    let mut unique = codes.clone();
+    unique.sort();
+    unique.dedup();
+    assert_eq!(unique.len(), codes.len());
+
+    // Runtime errors keep the code of the error they wrap.
+    let error = Error::runtime(1, 1, Error::type_error("add", "nil"), Vec::new());
+    assert_eq!(error.code(), "R0003");
+    for error in [
+        Error::unexpected_char('@', 1, 1),
+        Error::too_many_constants(1, 1),
+        Error::stack_overflow(),
+        Error::division_by_zero(),
+        Error::native_error("clock", "failed"),
+        Error::checksum_mismatch(0, 1),
+    ] {
+        assert!(explain(error.code()).is_some());
+    }
+}
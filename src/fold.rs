@@ -0,0 +1,321 @@
+use crate::ast::{self, ExpressionStmt, Position, Statement};
+use crate::gc::Heap;
+use crate::value::Value;
+use std::mem;
+
+/// The value of a constant expression. Strings stay Rust strings while an
+/// expression is being folded, so only the strings left in the program are
+/// interned.
+#[derive(Clone)]
+enum Folded {
+    Value(Value),
+    Text(String),
+}
+
+impl Folded {
+    fn is_falsey(&self) -> bool {
+        match self {
+            Folded::Value(value) => value.is_falsey(),
+            Folded::Text(_) => false,
+        }
+    }
+
+    fn to_value(&self, heap: &Heap) -> Value {
+        match self {
+            Folded::Value(value) => value.clone(),
+            Folded::Text(s) => Value::string(heap, s.as_str()),
+        }
+    }
+
+    /// The literal expression that evaluates to this value.
+    fn into_literal(self, position: Position) -> ExpressionStmt {
+        match self {
+            Folded::Value(Value::Number(x)) => ExpressionStmt::Number(x, position),
+            Folded::Value(Value::Bool(b)) => ExpressionStmt::Bool(b, position),
+            Folded::Value(Value::String(s)) => ExpressionStmt::String(s.as_str().into(), position),
+            Folded::Value(Value::Nil) => ExpressionStmt::Nil(position),
+            Folded::Text(s) => ExpressionStmt::String(s, position),
+            Folded::Value(_) => unreachable!("only literals fold"),
+        }
+    }
+}
+
+/// Replaces every operator expression in `program` whose operands are
+/// constant with the literal it evaluates to, in one bottom-up pass.
+///
+/// Operators are applied through `Value`, so they fold exactly as the VM
+/// would run them, and operations the VM rejects, such as `1 + "a"`, are left
+/// for it to report. Strings the pass needs as values are interned on `heap`.
+pub(crate) fn fold(program: &mut [Statement], heap: &Heap) {
+    let mut folder = Folder { heap };
+    for statement in program {
+        folder.statement(statement);
+    }
+}
+
+struct Folder<'h> {
+    heap: &'h Heap,
+}
+
+impl Folder<'_> {
+    fn statement(&mut self, statement: &mut Statement) {
+        match statement {
+            Statement::Expression(expr) => {
+                self.expression(expr);
+            }
+            Statement::Print(print_stmt) => {
+                self.expression(&mut print_stmt.expr);
+            }
+            Statement::VarDeclaration(_, initializer) => {
+                if let Some(initializer) = initializer {
+                    self.expression(initializer);
+                }
+            }
+            Statement::Block(statements) => {
+                for statement in statements {
+                    self.statement(statement);
+                }
+            }
+            Statement::If(if_stmt) => {
+                self.expression(&mut if_stmt.condition);
+                self.statement(&mut if_stmt.then_branch);
+                if let Some(else_branch) = &mut if_stmt.else_branch {
+                    self.statement(else_branch);
+                }
+            }
+            Statement::While(while_stmt) => {
+                self.expression(&mut while_stmt.condition);
+                self.statement(&mut while_stmt.body);
+            }
+            Statement::For(for_stmt) => {
+                if let Some(initializer) = &mut for_stmt.initializer {
+                    self.statement(initializer);
+                }
+                for expr in [&mut for_stmt.condition, &mut for_stmt.increment]
+                    .into_iter()
+                    .flatten()
+                {
+                    self.expression(expr);
+                }
+                self.statement(&mut for_stmt.body);
+            }
+            Statement::Function(decl) => self.function(decl),
+            Statement::Class(decl) => {
+                for method in &mut decl.methods {
+                    self.function(method);
+                }
+            }
+            Statement::Return(return_stmt) => {
+                if let Some(value) = &mut return_stmt.value {
+                    self.expression(value);
+                }
+            }
+        }
+    }
+
+    fn function(&mut self, decl: &mut ast::FunctionDecl) {
+        for statement in &mut decl.body {
+            self.statement(statement);
+        }
+    }
+
+    /// Folds the constant parts of `expr`, returning its value when all of it
+    /// is constant. A folded operator is replaced with its literal.
+    fn expression(&mut self, expr: &mut ExpressionStmt) -> Option<Folded> {
+        match expr {
+            ExpressionStmt::Number(x, _) => Some(Folded::Value(Value::from(*x))),
+            ExpressionStmt::String(s, _) => Some(Folded::Text(s.clone())),
+            ExpressionStmt::Bool(b, _) => Some(Folded::Value(Value::from(*b))),
+            ExpressionStmt::Nil(_) => Some(Folded::Value(Value::nil())),
+            ExpressionStmt::Identifier(_) | ExpressionStmt::This(_) | ExpressionStmt::Super(..) => {
+                None
+            }
+            ExpressionStmt::Assign(_, value) => {
+                self.expression(value);
+                None
+            }
+            ExpressionStmt::Call(callee, arguments, _) => {
+                self.expression(callee);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+                None
+            }
+            ExpressionStmt::Get(object, _) => {
+                self.expression(object);
+                None
+            }
+            ExpressionStmt::Set(object, _, value) => {
+                self.expression(object);
+                self.expression(value);
+                None
+            }
+            ExpressionStmt::Unary(op, operand) => {
+                let position = position(op);
+                let value = self.expression(operand)?;
+                let result = match op.symbol.as_str() {
+                    "+" => Folded::Value(
+                        value
+                            .to_value(self.heap)
+                            .checked_neg()
+                            .and_then(Value::checked_neg)
+                            .ok()?,
+                    ),
+                    "-" => Folded::Value(value.to_value(self.heap).checked_neg().ok()?),
+                    "!" => Folded::Value(Value::from(value.is_falsey())),
+                    _ => return None,
+                };
+                *expr = result.clone().into_literal(position);
+                Some(result)
+            }
+            ExpressionStmt::Binary(..) => self.binary(expr),
+        }
+    }
+
+    /// Folds a chain of binary operators from the left, in a loop rather than
+    /// one recursive call per operator, and without copying the partial
+    /// results, so long chains like `"a" + "b" + ... + "z"` fold in linear time.
+    fn binary(&mut self, expr: &mut ExpressionStmt) -> Option<Folded> {
+        let mut lhs = mem::replace(expr, ExpressionStmt::Nil(Position::default()));
+        let mut operations = Vec::new();
+        while let ExpressionStmt::Binary(op, expr_pair) = lhs {
+            let (next, rhs) = *expr_pair;
+            operations.push((op, rhs));
+            lhs = next;
+        }
+
+        let mut value = self.expression(&mut lhs);
+        // Where the operator that computed `value` is, while `lhs` still
+        // holds its operands.
+        let mut folded_at = None;
+        for (op, mut rhs) in operations.into_iter().rev() {
+            let rhs_value = self.expression(&mut rhs);
+            if let (Some(lhs_value), Some(rhs_value)) = (&mut value, rhs_value)
+                && self.apply(&op.symbol, lhs_value, rhs_value)
+            {
+                folded_at = Some(position(&op));
+                continue;
+            }
+            // The chain stops folding here, keep what folded so far.
+            if let (Some(value), Some(position)) = (value.take(), folded_at.take()) {
+                lhs = value.into_literal(position);
+            }
+            lhs = ExpressionStmt::Binary(op, Box::new((lhs, rhs)));
+        }
+
+        *expr = match (&value, folded_at) {
+            (Some(value), Some(position)) => value.clone().into_literal(position),
+            _ => lhs,
+        };
+        value
+    }
+
+    /// Applies a binary operator to constant operands as the VM would, storing
+    /// the result in `lhs`. Returns false, leaving `lhs` as it was, for
+    /// operations the VM would reject.
+    fn apply(&self, op: &str, lhs: &mut Folded, rhs: Folded) -> bool {
+        let result = match (op, &mut *lhs, rhs) {
+            ("and", lhs, _) if lhs.is_falsey() => return true,
+            ("or", lhs, _) if !lhs.is_falsey() => return true,
+            ("and" | "or", _, rhs) => Ok(rhs),
+            ("+", Folded::Text(lhs), Folded::Text(rhs)) => {
+                lhs.push_str(&rhs);
+                return true;
+            }
+            (op, lhs, rhs) => {
+                let (lhs, rhs) = (lhs.to_value(self.heap), rhs.to_value(self.heap));
+                // Compiled as the negation of the opposite comparison.
+                let not = |value: Value| Value::from(value.is_falsey());
+                match op {
+                    "+" => lhs.checked_add(rhs, self.heap),
+                    "-" => lhs.checked_sub(rhs),
+                    "*" => lhs.checked_mul(rhs),
+                    "/" => lhs.checked_div(rhs),
+                    "==" => Ok(Value::from(lhs == rhs)),
+                    "!=" => Ok(Value::from(lhs != rhs)),
+                    ">" => lhs.checked_greater(rhs),
+                    ">=" => lhs.checked_less(rhs).map(not),
+                    "<" => lhs.checked_less(rhs),
+                    "<=" => lhs.checked_greater(rhs).map(not),
+                    _ => return false,
+                }
+                .map(Folded::Value)
+            }
+        };
+        match result {
+            Ok(result) => {
+                *lhs = result;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+fn position(op: &ast::Operator) -> Position {
+    Position {
+        line: op.line,
+        column: op.column,
+    }
+}
+
+#[test]
+fn tests() {
+    let folded = |input: &str| {
+        let heap = Heap::new();
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        fold(&mut program, &heap);
+        let exprs: Vec<_> = program
+            .iter()
+            .map(|statement| match statement {
+                Statement::Print(print_stmt) => print_stmt.expr.to_string(),
+                Statement::Expression(expr) => expr.to_string(),
+                _ => panic!("expected an expression"),
+            })
+            .collect();
+        (exprs.join("; "), heap)
+    };
+
+    {
+        let (program, heap) = folded("print \"a\" + \"b\" + \"c\";");
+        assert_eq!(program, "\"abc\"");
+        // Only what is left in the program gets interned.
+        assert_eq!(heap.find_string("ab"), None);
+        assert_eq!(folded("print -(1 + 2) * 3 >= -9;").0, "true");
+        assert_eq!(folded("print 2 >= 2 and 1 / 4;").0, "0.25");
+        assert_eq!(folded("print 2 <= 1; print !nil;").0, "false; true");
+        assert_eq!(folded("print nil or \"a\" == \"a\";").0, "true");
+        assert_eq!(folded("print 1; print -x;").0, "1; (- x)");
+    }
+    {
+        // Only the constant parts of other expressions are folded.
+        assert_eq!(
+            folded("print 1 + 2 + x + (3 * 4); f(-1, !nil);").0,
+            "(+ (+ 3 x) 12); (call f -1 true)"
+        );
+        // Operations the VM rejects are left for it to report.
+        assert_eq!(
+            folded("print 1 + \"a\" + (\"b\" + \"c\");").0,
+            "(+ (+ 1 \"a\") \"bc\")"
+        );
+        assert_eq!(folded("print -\"a\" + 1;").0, "(+ (- \"a\") 1)");
+        assert_eq!(folded("print 1 / (2 - 2);").0, "(/ 1 0)");
+        assert_eq!(folded("print +(2 * 3); print +\"a\";").0, "6; (+ \"a\")");
+        assert_eq!(folded("print \"a\" + \"b\" + 1;").0, "(+ \"ab\" 1)");
+    }
+    {
+        // Function and method bodies are folded too.
+        let heap = Heap::new();
+        let mut program = crate::Parser::new("fun f() { return 1 + 1; }")
+            .statements()
+            .unwrap();
+        fold(&mut program, &heap);
+        let [Statement::Function(decl)] = &program[..] else {
+            panic!("expected a function");
+        };
+        let [Statement::Return(return_stmt)] = &decl.body[..] else {
+            panic!("expected a return");
+        };
+        assert_eq!(return_stmt.value.as_ref().unwrap().to_string(), "2");
+    }
+}
@@ -0,0 +1,369 @@
+use std::borrow::Borrow;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::ops::Deref;
+use std::ptr::NonNull;
+
+/// Heap size at which the first collection happens.
+const INITIAL_GC_THRESHOLD: usize = 1024 * 1024;
+/// How much the heap may grow, relative to what survived, before the next collection.
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+/// Implemented by every type stored in the managed heap so the collector can
+/// find the objects it references.
+pub trait Trace {
+    fn trace(&self, tracer: &mut Tracer);
+
+    /// Bytes owned by the object outside its heap cell, such as string buffers.
+    fn heap_size(&self) -> usize {
+        0
+    }
+}
+
+struct GcBox<T: ?Sized> {
+    marked: Cell<bool>,
+    size: usize,
+    value: T,
+}
+
+/// A pointer to an object owned by a `Heap`.
+///
+/// Handles are plain pointers: they stay valid only while the object is
+/// reachable from the roots the VM hands to `Heap::collect`, and never outlive
+/// the heap that allocated them. Neither handles nor the heap leave the crate.
+/// They compare and hash by identity.
+pub(crate) struct Gc<T: Trace + 'static> {
+    ptr: NonNull<GcBox<T>>,
+}
+
+impl<T: Trace + 'static> Gc<T> {
+    pub fn ptr_eq(lhs: &Self, rhs: &Self) -> bool {
+        lhs.ptr == rhs.ptr
+    }
+
+    fn is_marked(&self) -> bool {
+        // SAFETY: see `Deref`.
+        unsafe { self.ptr.as_ref() }.marked.get()
+    }
+}
+
+impl<T: Trace + 'static> PartialEq for Gc<T> {
+    fn eq(&self, other: &Self) -> bool {
+        Gc::ptr_eq(self, other)
+    }
+}
+
+impl<T: Trace + 'static> Eq for Gc<T> {}
+
+impl<T: Trace + 'static> Hash for Gc<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ptr.hash(state);
+    }
+}
+
+impl<T: Trace + 'static> Clone for Gc<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: Trace + 'static> Copy for Gc<T> {}
+
+impl<T: Trace + 'static> Deref for Gc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: the heap frees an object only once no root reaches it, and a
+        // handle that is still in use is reachable.
+        unsafe { &self.ptr.as_ref().value }
+    }
+}
+
+impl<T: Trace + fmt::Display + 'static> fmt::Display for Gc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", **self)
+    }
+}
+
+impl<T: Trace + fmt::Debug + 'static> fmt::Debug for Gc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", **self)
+    }
+}
+
+impl<T: Trace> Trace for RefCell<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.borrow().trace(tracer);
+    }
+}
+
+impl Trace for String {
+    fn trace(&self, _tracer: &mut Tracer) {}
+
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+/// An entry in the string table, hashed by contents so it can be looked up
+/// with a `&str`.
+struct Interned(Gc<String>);
+
+impl PartialEq for Interned {
+    fn eq(&self, other: &Self) -> bool {
+        *self.0 == *other.0
+    }
+}
+
+impl Eq for Interned {}
+
+impl Hash for Interned {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_str().hash(state);
+    }
+}
+
+impl Borrow<str> for Interned {
+    fn borrow(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+/// Marks objects reachable from the roots. Marked objects wait on the gray
+/// stack until their own references have been traced.
+#[derive(Default)]
+pub struct Tracer {
+    gray: Vec<NonNull<GcBox<dyn Trace>>>,
+}
+
+impl Tracer {
+    pub fn mark<T: Trace + 'static>(&mut self, object: Gc<T>) {
+        // SAFETY: handles given to the tracer point at live objects.
+        let gc_box = unsafe { object.ptr.as_ref() };
+        if !gc_box.marked.replace(true) {
+            self.gray.push(object.ptr);
+        }
+    }
+}
+
+/// Owns every object allocated by the compiler and the VM and frees the ones
+/// that are no longer reachable.
+///
+/// Allocation never collects by itself: the VM decides when to collect, at a
+/// point where every live value is reachable from its roots.
+pub(crate) struct Heap {
+    objects: RefCell<Vec<NonNull<GcBox<dyn Trace>>>>,
+    // Every interned string. Entries do not keep their string alive.
+    strings: RefCell<HashSet<Interned>>,
+    bytes_allocated: Cell<usize>,
+    next_gc: Cell<usize>,
+    // Allocations since the last collection.
+    allocations: Cell<usize>,
+    stress: bool,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            objects: RefCell::new(Vec::new()),
+            strings: RefCell::new(HashSet::new()),
+            bytes_allocated: Cell::new(0),
+            next_gc: Cell::new(INITIAL_GC_THRESHOLD),
+            allocations: Cell::new(0),
+            stress: false,
+        }
+    }
+
+    /// In stress mode `should_collect` holds after any allocation, so the VM
+    /// collects at the next instruction boundary, to shake out values it
+    /// forgot to root.
+    #[cfg(test)]
+    pub fn set_stress_mode(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    pub fn alloc<T: Trace + 'static>(&self, value: T) -> Gc<T> {
+        let size = mem::size_of::<GcBox<T>>() + value.heap_size();
+        let gc_box = Box::new(GcBox {
+            marked: Cell::new(false),
+            size,
+            value,
+        });
+        let ptr = NonNull::from(Box::leak(gc_box));
+        self.objects.borrow_mut().push(ptr);
+        self.bytes_allocated.set(self.bytes_allocated.get() + size);
+        self.allocations.set(self.allocations.get() + 1);
+        Gc { ptr }
+    }
+
+    /// Returns the one string object with these contents, allocating it the
+    /// first time it is seen.
+    pub fn intern(&self, s: impl AsRef<str> + Into<String>) -> Gc<String> {
+        if let Some(interned) = self.find_string(s.as_ref()) {
+            return interned;
+        }
+        let string = self.alloc(s.into());
+        self.strings.borrow_mut().insert(Interned(string));
+        string
+    }
+
+    /// Looks up an interned string without allocating it.
+    pub fn find_string(&self, s: &str) -> Option<Gc<String>> {
+        self.strings.borrow().get(s).map(|interned| interned.0)
+    }
+
+    #[cfg(test)]
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated.get()
+    }
+
+    #[cfg(test)]
+    pub fn object_count(&self) -> usize {
+        self.objects.borrow().len()
+    }
+
+    pub fn should_collect(&self) -> bool {
+        if self.stress {
+            self.allocations.get() > 0
+        } else {
+            self.bytes_allocated.get() > self.next_gc.get()
+        }
+    }
+
+    /// Frees every object not reachable from the objects `mark_roots` marks.
+    ///
+    /// # Safety
+    ///
+    /// Every handle into this heap that is used afterwards, including those
+    /// held by objects outside it, must be reachable from the marked roots.
+    /// Any other handle dangles once this returns.
+    pub(crate) unsafe fn collect(&self, mark_roots: impl FnOnce(&mut Tracer)) {
+        let mut tracer = Tracer::default();
+        mark_roots(&mut tracer);
+        while let Some(ptr) = tracer.gray.pop() {
+            // SAFETY: only live objects are ever pushed on the gray stack.
+            unsafe { ptr.as_ref() }.value.trace(&mut tracer);
+        }
+
+        self.strings
+            .borrow_mut()
+            .retain(|interned| interned.0.is_marked());
+
+        let mut freed = 0;
+        self.objects.borrow_mut().retain(|ptr| {
+            // SAFETY: every object in the list is live until it is freed here.
+            let gc_box = unsafe { ptr.as_ref() };
+            if gc_box.marked.replace(false) {
+                return true;
+            }
+            freed += gc_box.size;
+            // SAFETY: the object was allocated by `alloc` and nothing reachable
+            // refers to it any more.
+            drop(unsafe { Box::from_raw(ptr.as_ptr()) });
+            false
+        });
+
+        let remaining = self.bytes_allocated.get() - freed;
+        self.bytes_allocated.set(remaining);
+        self.next_gc
+            .set((remaining * GC_HEAP_GROW_FACTOR).max(INITIAL_GC_THRESHOLD));
+        self.allocations.set(0);
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for ptr in self.objects.get_mut().drain(..) {
+            // SAFETY: the heap owns all of its objects and is going away.
+            drop(unsafe { Box::from_raw(ptr.as_ptr()) });
+        }
+    }
+}
+
+#[test]
+fn tests() {
+    struct Node {
+        next: RefCell<Option<Gc<Node>>>,
+    }
+
+    impl Trace for Node {
+        fn trace(&self, tracer: &mut Tracer) {
+            if let Some(next) = *self.next.borrow() {
+                tracer.mark(next);
+            }
+        }
+    }
+
+    {
+        let heap = Heap::new();
+        let a = heap.alloc(Node {
+            next: RefCell::new(None),
+        });
+        let b = heap.alloc(Node {
+            next: RefCell::new(Some(a)),
+        });
+        heap.alloc(String::from("garbage"));
+        assert_eq!(heap.object_count(), 3);
+
+        // `a` survives through `b`, the string is unreachable.
+        // SAFETY: here and below, no handle is used after a collection frees it.
+        unsafe { heap.collect(|tracer| tracer.mark(b)) };
+        assert_eq!(heap.object_count(), 2);
+        assert!(Gc::ptr_eq(&b.next.borrow().unwrap(), &a));
+
+        unsafe { heap.collect(|_| {}) };
+        assert_eq!(heap.object_count(), 0);
+        assert_eq!(heap.bytes_allocated(), 0);
+    }
+    {
+        // Cycles are collected once nothing outside them is reachable.
+        let heap = Heap::new();
+        let a = heap.alloc(Node {
+            next: RefCell::new(None),
+        });
+        let b = heap.alloc(Node {
+            next: RefCell::new(Some(a)),
+        });
+        *a.next.borrow_mut() = Some(b);
+
+        unsafe { heap.collect(|tracer| tracer.mark(a)) };
+        assert_eq!(heap.object_count(), 2);
+        unsafe { heap.collect(|_| {}) };
+        assert_eq!(heap.object_count(), 0);
+    }
+    {
+        let mut heap = Heap::new();
+        heap.alloc(String::from("small"));
+        assert!(!heap.should_collect());
+
+        heap.set_stress_mode(true);
+        assert!(heap.should_collect());
+        unsafe { heap.collect(|_| {}) };
+        assert!(!heap.should_collect());
+    }
+    {
+        let heap = Heap::new();
+        let a = heap.intern("lox");
+        let b = heap.intern(String::from("lox"));
+        assert_eq!(a, b);
+        assert_ne!(a, heap.intern("lux"));
+        assert_eq!(heap.find_string("lox"), Some(a));
+        assert_eq!(heap.object_count(), 2);
+
+        // The table does not keep strings alive.
+        unsafe { heap.collect(|tracer| tracer.mark(a)) };
+        assert_eq!(heap.find_string("lox"), Some(a));
+        assert_eq!(heap.find_string("lux"), None);
+        unsafe { heap.collect(|_| {}) };
+        assert_eq!(heap.find_string("lox"), None);
+    }
+}
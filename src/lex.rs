@@ -41,6 +41,8 @@ pub enum TokenKind {
     True,
     Var,
     While,
+    /// Stands in for text the lexer rejected, so the parser can skip over it.
+    Error,
     Eof,
 }
 
@@ -96,6 +98,7 @@ impl fmt::Display for Token<'_> {
             TokenKind::True => write!(f, "TRUE <{line}, {column}> {lexeme}"),
             TokenKind::Var => write!(f, "VAR <{line}, {column}> {lexeme}"),
             TokenKind::While => write!(f, "WHILE <{line}, {column}> {lexeme}"),
+            TokenKind::Error => write!(f, "ERROR <{line}, {column}> {lexeme}"),
             TokenKind::Eof => write!(f, "EOF <{line}, {column}> {lexeme}"),
         }
     }
@@ -210,7 +213,7 @@ impl<'a> Iterator for Lexer<'a> {
                 }
                 '0'..='9' => Started::Number,
                 'a'..='z' | 'A'..='Z' | '_' => Started::Ident,
-                c if c == '\n' => {
+                '\n' => {
                     self.line += 1;
                     self.column = 1;
                     self.rest = chars.as_str();
@@ -221,14 +224,21 @@ impl<'a> Iterator for Lexer<'a> {
                     self.rest = chars.as_str();
                     continue;
                 }
-                c => return Some(Err(Error::unexpected_char(c, self.line, self.column))),
+                c => {
+                    let error = Error::unexpected_char(c, self.line, self.column);
+                    // Skip the character so lexing can carry on after it.
+                    self.column += c.len_utf8();
+                    self.rest = chars.as_str();
+                    return Some(Err(error));
+                }
             };
 
             match started {
                 Started::String => {
-                    if let Some(_) = chars.find(|&c| c == '"') {
+                    if chars.any(|c| c == '"') {
                         return self.emit_token(token_start, chars.as_str(), TokenKind::String);
                     } else {
+                        self.rest = "";
                         return Some(Err(Error::unterminated_string(self.line, self.column)));
                     }
                 }
@@ -247,7 +257,7 @@ impl<'a> Iterator for Lexer<'a> {
                 Started::Ident => {
                     let first_non_ident = token_start
                         .find(|c| !matches!(c, 'a'..='z' | 'A'..='Z' | '0'..='9' | '_'))
-                        .unwrap_or_else(|| token_start.len());
+                        .unwrap_or(token_start.len());
                     let lexeme = &token_start[..first_non_ident];
                     let kind = match lexeme {
                         "and" => TokenKind::And,
@@ -281,7 +291,7 @@ impl<'a> Iterator for Lexer<'a> {
                 Started::Number => {
                     let first_non_digit = token_start
                         .find(|c| !matches!(c, '.' | '0'..='9'))
-                        .unwrap_or_else(|| token_start.len());
+                        .unwrap_or(token_start.len());
                     return self.emit_token(
                         token_start,
                         &token_start[first_non_digit..],
@@ -305,4 +315,23 @@ fn test_lexer() {
     for token in tokens.unwrap() {
         assert_eq!(Some(token.to_string().as_str()), expected_lins.next());
     }
+
+    // Lexing carries on after a bad character.
+    let tokens: Vec<_> = Lexer::new("1 @ 2").collect();
+    assert!(matches!(
+        &tokens[..],
+        [
+            Ok(Token { lexeme: "1", .. }),
+            Err(Error::UnexpectedChar {
+                ch: '@',
+                line: 1,
+                column: 3
+            }),
+            Ok(Token {
+                lexeme: "2",
+                column: 5,
+                ..
+            }),
+        ]
+    ));
 }
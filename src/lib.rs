@@ -1,12 +1,21 @@
 pub mod ast;
+pub mod bytecode;
 pub mod compiler;
+pub mod diagnostic;
+pub mod disasm;
 pub mod error;
+pub mod explain;
+mod fold;
+mod gc;
 pub mod lex;
+pub mod native;
+pub mod object;
 pub mod parse;
 pub mod value;
+pub mod verify;
 pub mod vm;
 
 pub use error::Error;
 pub use lex::Lexer;
 pub use parse::Parser;
-pub use value::Value;
+pub(crate) use value::Value;
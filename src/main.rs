@@ -1,7 +1,10 @@
 use clap::{Parser, Subcommand};
-use loxemu::{Lexer, compiler, vm};
+use loxemu::{Error, Lexer, bytecode, diagnostic::Diagnostic, disasm, explain, vm::VM};
 use std::fs;
-use std::path::PathBuf;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+
+mod repl;
 
 #[derive(clap::Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -12,44 +15,204 @@ struct Args {
 
 #[derive(Subcommand, Debug)]
 enum Commands {
-    Lex { filename: PathBuf },
-    Parse { filename: PathBuf },
-    Run { filename: PathBuf },
+    Lex {
+        filename: PathBuf,
+        #[arg(long, value_enum, default_value_t)]
+        error_format: ErrorFormat,
+    },
+    Parse {
+        filename: PathBuf,
+        #[arg(long, value_enum, default_value_t)]
+        error_format: ErrorFormat,
+    },
+    /// Reports every error in a program without running it.
+    Check {
+        filename: PathBuf,
+        #[arg(long, value_enum, default_value_t)]
+        error_format: ErrorFormat,
+    },
+    Run {
+        filename: PathBuf,
+        #[arg(long, value_enum, default_value_t)]
+        error_format: ErrorFormat,
+    },
+    /// Compiles a program to a bytecode file that `exec` can run.
+    Compile {
+        filename: PathBuf,
+        /// Defaults to the input file with a `.loxc` extension.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t)]
+        error_format: ErrorFormat,
+    },
+    /// Runs a bytecode file written by `compile`.
+    Exec {
+        filename: PathBuf,
+        #[arg(long, value_enum, default_value_t)]
+        error_format: ErrorFormat,
+    },
+    /// Prints the bytecode of every function in a program.
+    Disasm {
+        filename: PathBuf,
+        #[arg(long, value_enum, default_value_t)]
+        format: DisasmFormat,
+        #[arg(long, value_enum, default_value_t)]
+        error_format: ErrorFormat,
+    },
+    /// Runs Lox interactively, keeping globals between inputs.
+    Repl,
+    /// Describes an error code, such as R0003, with an example and a fix.
+    Explain { code: String },
+}
+
+/// How `disasm` prints bytecode.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default)]
+enum DisasmFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// How errors are printed.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default)]
+enum ErrorFormat {
+    /// Source-annotated diagnostics.
+    #[default]
+    Human,
+    /// One JSON object per line.
+    Json,
 }
 
 fn main() -> Result<(), std::io::Error> {
     let args = Args::parse();
     match args.command {
-        Commands::Lex { filename } => {
-            let file_contents = fs::read_to_string(filename)?;
-            let lexer = Lexer::new(&file_contents);
-            let tokens: Result<Vec<_>, _> = lexer.collect();
-            let tokens = tokens.unwrap();
-            for token in tokens {
-                println!("{token}");
+        Commands::Lex {
+            filename,
+            error_format,
+        } => {
+            let file_contents = fs::read_to_string(&filename)?;
+            let mut errors = Vec::new();
+            for token in Lexer::new(&file_contents) {
+                match token {
+                    Ok(token) => println!("{token}"),
+                    Err(error) => errors.push(error),
+                }
+            }
+            if !errors.is_empty() {
+                report(&errors, &file_contents, &filename, error_format);
+                std::process::exit(65);
             }
         }
-        Commands::Parse { filename } => {
-            let file_contents = fs::read_to_string(filename)?;
+        Commands::Parse {
+            filename,
+            error_format,
+        } => {
+            let file_contents = fs::read_to_string(&filename)?;
             let mut parser = loxemu::Parser::new(&file_contents);
-            let expr = parser.expression().expect("Failed to parse expreesion");
-            println!("{expr}");
+            match parser.expression() {
+                Ok(expr) => println!("{expr}"),
+                Err(error) => {
+                    report(&[error], &file_contents, &filename, error_format);
+                    std::process::exit(65);
+                }
+            }
         }
-        Commands::Run { filename } => {
-            // TODO: Implement eval loop
-            let file_contents = fs::read_to_string(filename)?;
-            let mut parser = loxemu::Parser::new(&file_contents);
-            let stmt = parser.statement().expect("Failed to parse expreesion");
-            let mut chunk = compiler::compile(&stmt);
-            // TODO: Manually appending Return. Fix when complete.
-            chunk.emit(vm::OpCode::Return);
-            let mut vm = vm::VM::new(chunk);
-            vm.interpret().unwrap();
-            if vm.stack.len() == 1 {
-                println!("Top of the stack: {:?}", vm.stack.first());
+        Commands::Check {
+            filename,
+            error_format,
+        } => {
+            let file_contents = fs::read_to_string(&filename)?;
+            compile(&file_contents, &filename, error_format);
+        }
+        Commands::Run {
+            filename,
+            error_format,
+        } => {
+            let file_contents = fs::read_to_string(&filename)?;
+            let mut vm = compile(&file_contents, &filename, error_format);
+            if let Err(error) = vm.interpret() {
+                report(&[error], &file_contents, &filename, error_format);
+                // clox's exit code for runtime errors.
+                std::process::exit(70);
             }
         }
+        Commands::Compile {
+            filename,
+            output,
+            error_format,
+        } => {
+            let file_contents = fs::read_to_string(&filename)?;
+            let vm = compile(&file_contents, &filename, error_format);
+            let output = output.unwrap_or_else(|| filename.with_extension("loxc"));
+            fs::write(output, bytecode::serialize(vm.script()))?;
+        }
+        Commands::Exec {
+            filename,
+            error_format,
+        } => {
+            let bytes = fs::read(&filename)?;
+            // There is no source to quote, so diagnostics only give positions.
+            let mut vm = match VM::from_bytecode(&bytes) {
+                Ok(vm) => vm,
+                Err(error) => {
+                    report(&[error], "", &filename, error_format);
+                    std::process::exit(65);
+                }
+            };
+            if let Err(error) = vm.interpret() {
+                report(&[error], "", &filename, error_format);
+                std::process::exit(70);
+            }
+        }
+        Commands::Disasm {
+            filename,
+            format,
+            error_format,
+        } => {
+            let file_contents = fs::read_to_string(&filename)?;
+            let vm = compile(&file_contents, &filename, error_format);
+            match format {
+                DisasmFormat::Text => print!("{}", disasm::render(vm.script())),
+                DisasmFormat::Json => println!("{}", disasm::to_json(vm.script())),
+            }
+        }
+        Commands::Repl => repl::run().map_err(std::io::Error::other)?,
+        Commands::Explain { code } => match explain::explain(&code) {
+            Some(explanation) => println!("{explanation}"),
+            None => {
+                eprintln!("Unknown error code '{code}'");
+                std::process::exit(1);
+            }
+        },
     }
 
     Ok(())
 }
+
+/// Compiles a program into a VM ready to run it, or prints its errors and exits.
+fn compile(source: &str, filename: &Path, format: ErrorFormat) -> VM {
+    let errors = match loxemu::Parser::new(source).statements() {
+        Ok(mut program) => match VM::from_program(&mut program) {
+            Ok(vm) => return vm,
+            Err(error) => vec![error],
+        },
+        Err(errors) => errors,
+    };
+    report(&errors, source, filename, format);
+    // clox's exit code for compile errors.
+    std::process::exit(65);
+}
+
+/// Prints errors against the source they came from, in colour when stdout is a terminal.
+fn report(errors: &[Error], source: &str, filename: &Path, format: ErrorFormat) {
+    let colour = std::io::stdout().is_terminal();
+    let filename = filename.display().to_string();
+    for error in errors {
+        let diagnostic = Diagnostic::from(error);
+        match format {
+            // A blank line after each diagnostic keeps them apart.
+            ErrorFormat::Human => eprintln!("{}", diagnostic.render(source, &filename, colour)),
+            ErrorFormat::Json => eprintln!("{}", diagnostic.to_json(source, &filename)),
+        }
+    }
+}
@@ -0,0 +1,12 @@
+use crate::Value;
+use crate::error::Error;
+use crate::vm::VM;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds since the Unix epoch, for timing Lox programs.
+pub(crate) fn clock(_vm: &mut VM, _args: &[Value]) -> Result<Value, Error> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| Error::native_error("clock", e.to_string()))?;
+    Ok(Value::from(now.as_secs_f64()))
+}
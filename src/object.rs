@@ -0,0 +1,231 @@
+use crate::Value;
+use crate::error::Error;
+use crate::gc::{Gc, Trace, Tracer};
+use crate::vm::{Chunk, VM};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+
+/// A compiled function. The top-level script is a function without a name.
+pub struct Function {
+    pub name: Option<String>,
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+}
+
+impl Function {
+    pub fn new(name: &str, arity: usize, chunk: Chunk) -> Self {
+        Self {
+            name: Some(name.into()),
+            arity,
+            upvalue_count: 0,
+            chunk,
+        }
+    }
+
+    pub fn script(chunk: Chunk) -> Self {
+        Self {
+            name: None,
+            arity: 0,
+            upvalue_count: 0,
+            chunk,
+        }
+    }
+}
+
+impl Trace for Function {
+    fn trace(&self, tracer: &mut Tracer) {
+        for constant in &self.chunk.constants {
+            constant.trace(tracer);
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "<fn {name}>"),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+/// Signature of a function implemented in Rust and callable from Lox.
+pub(crate) type NativeFunction = fn(&mut VM, &[Value]) -> Result<Value, Error>;
+
+/// A Rust function registered with the VM under a global name.
+pub(crate) struct NativeFn {
+    pub name: String,
+    pub arity: usize,
+    pub function: NativeFunction,
+}
+
+impl Trace for NativeFn {
+    fn trace(&self, _tracer: &mut Tracer) {}
+}
+
+impl fmt::Display for NativeFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<native fn {}>", self.name)
+    }
+}
+
+impl fmt::Debug for NativeFn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+/// A variable captured by a closure. It points into the VM stack while the
+/// variable is in scope and owns the value once the variable is closed.
+pub(crate) enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+impl Trace for Upvalue {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Upvalue::Closed(val) = self {
+            val.trace(tracer);
+        }
+    }
+}
+
+/// A function together with the variables it captured when it was created.
+pub(crate) struct Closure {
+    pub function: Gc<Function>,
+    pub upvalues: Vec<Gc<RefCell<Upvalue>>>,
+}
+
+impl Closure {
+    pub fn new(function: Gc<Function>) -> Self {
+        Self {
+            function,
+            upvalues: Vec::new(),
+        }
+    }
+}
+
+impl Trace for Closure {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self.function);
+        for upvalue in &self.upvalues {
+            tracer.mark(*upvalue);
+        }
+    }
+}
+
+impl fmt::Display for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.function)
+    }
+}
+
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+pub(crate) struct Class {
+    pub name: String,
+    pub methods: HashMap<Gc<String>, Gc<Closure>>,
+}
+
+impl Class {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.into(),
+            methods: HashMap::new(),
+        }
+    }
+}
+
+impl Trace for Class {
+    fn trace(&self, tracer: &mut Tracer) {
+        for (name, method) in &self.methods {
+            tracer.mark(*name);
+            tracer.mark(*method);
+        }
+    }
+}
+
+impl fmt::Display for Class {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl fmt::Debug for Class {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+pub(crate) struct Instance {
+    pub class: Gc<RefCell<Class>>,
+    pub fields: HashMap<Gc<String>, Value>,
+}
+
+impl Instance {
+    pub fn new(class: Gc<RefCell<Class>>) -> Self {
+        Self {
+            class,
+            fields: HashMap::new(),
+        }
+    }
+}
+
+impl Trace for Instance {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(self.class);
+        for (name, field) in &self.fields {
+            tracer.mark(*name);
+            field.trace(tracer);
+        }
+    }
+}
+
+impl fmt::Display for Instance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} instance", self.class.borrow().name)
+    }
+}
+
+impl fmt::Debug for Instance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")
+    }
+}
+
+/// A method looked up on an instance, remembering the instance as `this`.
+pub(crate) struct BoundMethod {
+    pub receiver: Value,
+    pub method: Gc<Closure>,
+}
+
+impl Trace for BoundMethod {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.receiver.trace(tracer);
+        tracer.mark(self.method);
+    }
+}
+
+impl fmt::Display for BoundMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.method)
+    }
+}
+
+impl fmt::Debug for BoundMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{self}")
+    }
+}
@@ -3,31 +3,48 @@ use crate::{
     error::Error,
     lex::{Token, TokenKind},
 };
+use std::collections::VecDeque;
+use std::mem;
 
 pub struct Parser<'a> {
     tokens: Vec<Token<'a>>,
     current: usize,
+    // Lexer errors, one per `TokenKind::Error` token, not yet passed by the parser.
+    lex_errors: VecDeque<Error>,
+    // Every error found so far, in source order.
+    errors: Vec<Error>,
 }
 
 type ParseResult<T> = Result<T, Error>;
 
+/// Calls and function declarations are limited by the one byte operand of `Call`.
+const MAX_ARGUMENTS: usize = 255;
+
 fn infix_binding_power(kind: TokenKind) -> Option<(u8, u8)> {
     match kind {
         TokenKind::Equal => Some((2, 1)),
-        TokenKind::Or | TokenKind::And => Some((3, 4)),
-        TokenKind::EqualEqual | TokenKind::BangEqual => Some((5, 6)),
+        TokenKind::Or => Some((3, 4)),
+        TokenKind::And => Some((5, 6)),
+        TokenKind::EqualEqual | TokenKind::BangEqual => Some((7, 8)),
         TokenKind::Greater | TokenKind::Less | TokenKind::LessEqual | TokenKind::GreaterEqual => {
-            Some((7, 8))
+            Some((9, 10))
         }
-        TokenKind::Plus | TokenKind::Minus => Some((9, 10)),
-        TokenKind::Star | TokenKind::Slash => Some((11, 12)),
+        TokenKind::Plus | TokenKind::Minus => Some((11, 12)),
+        TokenKind::Star | TokenKind::Slash => Some((13, 14)),
+        _ => None,
+    }
+}
+
+fn postfix_binding_power(kind: TokenKind) -> Option<u8> {
+    match kind {
+        TokenKind::LeftParen | TokenKind::Dot => Some(53),
         _ => None,
     }
 }
 
 fn prefix_binding_power(kind: TokenKind) -> u8 {
     match kind {
-        TokenKind::Plus | TokenKind::Minus => 51,
+        TokenKind::Plus | TokenKind::Minus | TokenKind::Bang => 51,
         _ => unreachable!(),
     }
 }
@@ -35,10 +52,27 @@ fn prefix_binding_power(kind: TokenKind) -> u8 {
 // TODO: Consider iterating through the colleciton of tokens
 impl<'a> Parser<'a> {
     pub fn new(input: &'a str) -> Self {
-        let lexer = Lexer::new(input);
-        let tokens: Result<Vec<_>, crate::error::Error> = lexer.collect();
-        // TODO: Propagate errors.
-        let mut tokens = tokens.unwrap();
+        let mut tokens = Vec::new();
+        let mut lex_errors = VecDeque::new();
+        for token in Lexer::new(input) {
+            match token {
+                Ok(token) => tokens.push(token),
+                Err(error) => {
+                    let (line, column) = match error {
+                        Error::UnexpectedChar { line, column, .. }
+                        | Error::UnterminatedString { line, column } => (line, column),
+                        _ => unreachable!("the lexer only reports lexing errors"),
+                    };
+                    tokens.push(Token {
+                        kind: TokenKind::Error,
+                        lexeme: "",
+                        line,
+                        column,
+                    });
+                    lex_errors.push_back(error);
+                }
+            }
+        }
         tokens.push(Token {
             kind: TokenKind::Eof,
             lexeme: "",
@@ -46,7 +80,12 @@ impl<'a> Parser<'a> {
             line: tokens.last().map(|t| t.line).unwrap_or(0),
             column: tokens.last().map(|t| t.column).unwrap_or(0),
         });
-        Self { tokens, current: 0 }
+        Self {
+            tokens,
+            current: 0,
+            lex_errors,
+            errors: Vec::new(),
+        }
     }
 
     fn peek(&self) -> &Token<'_> {
@@ -58,8 +97,15 @@ impl<'a> Parser<'a> {
     fn advance(&mut self) {
         // Note: Doesn't advance the current token past Eof. Since Eof is mandatory
         // we can safely unwrap the get call.
-        if self.tokens.get(self.current).unwrap().kind != TokenKind::Eof {
-            self.current += 1;
+        match self.tokens.get(self.current).unwrap().kind {
+            TokenKind::Eof => {}
+            TokenKind::Error => {
+                // Lexer errors are reported as the parser passes them, which
+                // keeps them in source order with parse errors.
+                self.errors.extend(self.lex_errors.pop_front());
+                self.current += 1;
+            }
+            _ => self.current += 1,
         }
     }
 
@@ -83,17 +129,83 @@ impl<'a> Parser<'a> {
         Err(Error::unexpected_token(Some(kind), current_token))
     }
 
-    pub fn statements(&mut self) -> Vec<ast::Statement> {
-        todo!("Parse statement");
+    /// Parses the whole program, or returns every lexer and parser error in it.
+    pub fn statements(&mut self) -> Result<Vec<ast::Statement>, Vec<Error>> {
+        let mut statements = Vec::new();
+        while !self.check(TokenKind::Eof) {
+            statements.extend(self.declaration());
+        }
+        if self.errors.is_empty() {
+            Ok(statements)
+        } else {
+            Err(mem::take(&mut self.errors))
+        }
     }
 
-    pub fn statement(&mut self) -> Result<ast::Statement, Error> {
+    /// Parses a declaration. A malformed one is recorded as an error and
+    /// skipped up to the start of the next statement.
+    pub fn declaration(&mut self) -> Option<ast::Statement> {
+        match self.try_declaration() {
+            Ok(statement) => Some(statement),
+            Err(error) => {
+                // A rejected token already has its lexer error.
+                if !matches!(
+                    error,
+                    Error::UnexpectedToken {
+                        found: TokenKind::Error,
+                        ..
+                    }
+                ) {
+                    self.errors.push(error);
+                }
+                self.synchronize();
+                None
+            }
+        }
+    }
+
+    /// Skips tokens until just past a `;` or up to a keyword that starts a
+    /// statement. The token that caused the error may itself be that keyword,
+    /// so it is checked before being skipped.
+    fn synchronize(&mut self) {
+        while !self.check(TokenKind::Eof) {
+            match self.peek().kind {
+                TokenKind::Class
+                | TokenKind::Fun
+                | TokenKind::Var
+                | TokenKind::For
+                | TokenKind::If
+                | TokenKind::While
+                | TokenKind::Print
+                | TokenKind::Return => return,
+                TokenKind::Semicolon => {
+                    self.advance();
+                    return;
+                }
+                _ => self.advance(),
+            }
+        }
+    }
+
+    fn try_declaration(&mut self) -> ParseResult<ast::Statement> {
         match self.peek().kind {
             TokenKind::Var => self.var_declaration(),
+            TokenKind::Class => self.class_declaration(),
+            TokenKind::Fun => {
+                // Advance fun kw matched here.
+                self.advance();
+                Ok(ast::Statement::Function(self.function()?))
+            }
+            _ => self.statement(),
+        }
+    }
+
+    pub fn statement(&mut self) -> ParseResult<ast::Statement> {
+        match self.peek().kind {
             TokenKind::If => self.if_statement(),
             TokenKind::For => self.for_statement(),
             TokenKind::Print => self.print_statement(),
-            TokenKind::Return => todo!(),
+            TokenKind::Return => self.return_statement(),
             TokenKind::While => self.while_statement(),
             TokenKind::LeftBrace => self.group_statement(),
             _ => self.expression_statement(),
@@ -112,16 +224,30 @@ impl<'a> Parser<'a> {
                     .lexeme
                     .parse()
                     .map_err(|_| Error::invalid_number(current_token))?;
-                ast::ExpressionStmt::Number(val)
+                ast::ExpressionStmt::Number(val, current_token.into())
+            }
+            TokenKind::String => {
+                let token = self.next();
+                let lexeme = token.lexeme;
+                // Strip the surrounding quotes kept in the lexeme.
+                ast::ExpressionStmt::String(lexeme[1..lexeme.len() - 1].into(), token.into())
             }
-            TokenKind::Ident => ast::ExpressionStmt::Identifier(self.next().lexeme.into()),
-            TokenKind::Plus | TokenKind::Minus => {
+            TokenKind::True => ast::ExpressionStmt::Bool(true, self.next().into()),
+            TokenKind::False => ast::ExpressionStmt::Bool(false, self.next().into()),
+            TokenKind::Nil => ast::ExpressionStmt::Nil(self.next().into()),
+            TokenKind::Ident => ast::ExpressionStmt::Identifier(self.next().into()),
+            TokenKind::This => ast::ExpressionStmt::This(self.next().into()),
+            TokenKind::Super => {
+                let keyword = self.next().into();
+                self.expect(TokenKind::Dot)?;
+                let method = self.peek().into();
+                self.expect(TokenKind::Ident)?;
+                ast::ExpressionStmt::Super(keyword, method)
+            }
+            TokenKind::Plus | TokenKind::Minus | TokenKind::Bang => {
                 let tok = self.next();
                 let bp = prefix_binding_power(tok.kind);
-                ast::ExpressionStmt::Unary(
-                    tok.lexeme.into(),
-                    Box::new(self.expr_with_binding_power(bp)?),
-                )
+                ast::ExpressionStmt::Unary(tok.into(), Box::new(self.expr_with_binding_power(bp)?))
             }
             TokenKind::LeftParen => {
                 self.advance();
@@ -134,15 +260,49 @@ impl<'a> Parser<'a> {
             }
         };
         loop {
+            if let Some(bp) = postfix_binding_power(self.peek().kind) {
+                if bp < min_bp {
+                    break;
+                }
+                if self.check(TokenKind::Dot) {
+                    self.advance();
+                    let name = self.peek().into();
+                    self.expect(TokenKind::Ident)?;
+                    lhs = ast::ExpressionStmt::Get(Box::new(lhs), name);
+                    continue;
+                }
+                let paren = self.next().into();
+                let arguments = self.arguments()?;
+                lhs = ast::ExpressionStmt::Call(Box::new(lhs), arguments, paren);
+                continue;
+            }
             if let Some((l_bp, r_bp)) = infix_binding_power(self.peek().kind) {
                 if l_bp < min_bp {
                     break;
                 }
-                // TODO: refactor operator to string properly.
-                let s = self.peek().lexeme.to_owned();
-                self.advance();
+                if self.check(TokenKind::Equal) {
+                    if !matches!(
+                        lhs,
+                        ast::ExpressionStmt::Identifier(_) | ast::ExpressionStmt::Get(..)
+                    ) {
+                        return Err(Error::invalid_assignment_target(self.peek()));
+                    }
+                    self.advance();
+                    let value = Box::new(self.expr_with_binding_power(r_bp)?);
+                    lhs = match lhs {
+                        ast::ExpressionStmt::Identifier(name) => {
+                            ast::ExpressionStmt::Assign(name, value)
+                        }
+                        ast::ExpressionStmt::Get(object, name) => {
+                            ast::ExpressionStmt::Set(object, name, value)
+                        }
+                        _ => unreachable!(),
+                    };
+                    continue;
+                }
+                let op = self.next().into();
                 let rhs = self.expr_with_binding_power(r_bp)?;
-                lhs = ast::ExpressionStmt::Binary(s, Box::new((lhs, rhs)));
+                lhs = ast::ExpressionStmt::Binary(op, Box::new((lhs, rhs)));
                 continue;
             }
             break;
@@ -150,6 +310,55 @@ impl<'a> Parser<'a> {
         Ok(lhs)
     }
 
+    /// Parses a call's argument list after its opening parenthesis.
+    fn arguments(&mut self) -> ParseResult<Vec<ast::ExpressionStmt>> {
+        let mut arguments = Vec::new();
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                if arguments.len() == MAX_ARGUMENTS {
+                    return Err(Error::too_many_arguments(self.peek()));
+                }
+                arguments.push(self.expression()?);
+                if !self.check(TokenKind::Comma) {
+                    break;
+                }
+                self.advance();
+            }
+        }
+        self.expect(TokenKind::RightParen)?;
+        Ok(arguments)
+    }
+
+    /// Parses a function's name, parameters and body.
+    fn function(&mut self) -> ParseResult<ast::FunctionDecl> {
+        let name = self.peek().into();
+        self.expect(TokenKind::Ident)?;
+        self.expect(TokenKind::LeftParen)?;
+        let mut params = Vec::new();
+        if !self.check(TokenKind::RightParen) {
+            loop {
+                if params.len() == MAX_ARGUMENTS {
+                    return Err(Error::too_many_parameters(self.peek()));
+                }
+                params.push(self.peek().into());
+                self.expect(TokenKind::Ident)?;
+                if !self.check(TokenKind::Comma) {
+                    break;
+                }
+                self.advance();
+            }
+        }
+        self.expect(TokenKind::RightParen)?;
+        if !self.check(TokenKind::LeftBrace) {
+            return Err(Error::unexpected_token(
+                Some(TokenKind::LeftBrace),
+                self.peek(),
+            ));
+        }
+        let body = self.block()?;
+        Ok(ast::FunctionDecl { name, params, body })
+    }
+
     fn expression_statement(&mut self) -> ParseResult<ast::Statement> {
         let expr = self.expression()?;
         self.expect(TokenKind::Semicolon)?;
@@ -164,28 +373,134 @@ impl<'a> Parser<'a> {
         Ok(ast::Statement::Print(ast::PrintStmt { expr }))
     }
 
-    fn if_statement(&self) -> ParseResult<ast::Statement> {
-        todo!()
+    fn if_statement(&mut self) -> ParseResult<ast::Statement> {
+        // Advance if kw matched in caller.
+        self.advance();
+        self.expect(TokenKind::LeftParen)?;
+        let condition = self.expression()?;
+        self.expect(TokenKind::RightParen)?;
+        let then_branch = Box::new(self.statement()?);
+        let else_branch = if self.check(TokenKind::Else) {
+            self.advance();
+            Some(Box::new(self.statement()?))
+        } else {
+            None
+        };
+        Ok(ast::Statement::If(ast::IfStmt {
+            condition,
+            then_branch,
+            else_branch,
+        }))
+    }
+
+    fn for_statement(&mut self) -> ParseResult<ast::Statement> {
+        // Advance for kw matched in caller.
+        self.advance();
+        self.expect(TokenKind::LeftParen)?;
+        let initializer = match self.peek().kind {
+            TokenKind::Semicolon => {
+                self.advance();
+                None
+            }
+            TokenKind::Var => Some(Box::new(self.var_declaration()?)),
+            _ => Some(Box::new(self.expression_statement()?)),
+        };
+        let condition = if self.check(TokenKind::Semicolon) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.expect(TokenKind::Semicolon)?;
+        let increment = if self.check(TokenKind::RightParen) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.expect(TokenKind::RightParen)?;
+        let body = Box::new(self.statement()?);
+        Ok(ast::Statement::For(ast::ForStmt {
+            initializer,
+            condition,
+            increment,
+            body,
+        }))
     }
 
-    fn for_statement(&self) -> ParseResult<ast::Statement> {
-        todo!()
+    fn while_statement(&mut self) -> ParseResult<ast::Statement> {
+        // Advance while kw matched in caller.
+        self.advance();
+        self.expect(TokenKind::LeftParen)?;
+        let condition = self.expression()?;
+        self.expect(TokenKind::RightParen)?;
+        let body = Box::new(self.statement()?);
+        Ok(ast::Statement::While(ast::WhileStmt { condition, body }))
     }
 
-    fn while_statement(&self) -> ParseResult<ast::Statement> {
-        todo!()
+    fn return_statement(&mut self) -> ParseResult<ast::Statement> {
+        let (line, column) = (self.peek().line, self.peek().column);
+        // Advance return kw matched in caller.
+        self.advance();
+        let value = if self.check(TokenKind::Semicolon) {
+            None
+        } else {
+            Some(self.expression()?)
+        };
+        self.expect(TokenKind::Semicolon)?;
+        Ok(ast::Statement::Return(ast::ReturnStmt {
+            value,
+            line,
+            column,
+        }))
     }
 
-    fn group_statement(&self) -> ParseResult<ast::Statement> {
-        todo!()
+    fn group_statement(&mut self) -> ParseResult<ast::Statement> {
+        Ok(ast::Statement::Block(self.block()?))
+    }
+
+    fn block(&mut self) -> ParseResult<Vec<ast::Statement>> {
+        // Advance left brace matched in caller.
+        self.advance();
+        let mut statements = Vec::new();
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            statements.extend(self.declaration());
+        }
+        self.expect(TokenKind::RightBrace)?;
+        Ok(statements)
+    }
+
+    fn class_declaration(&mut self) -> ParseResult<ast::Statement> {
+        // Advance class kw matched in caller.
+        self.advance();
+        let name = self.peek().into();
+        self.expect(TokenKind::Ident)?;
+        let superclass = if self.check(TokenKind::Less) {
+            self.advance();
+            let superclass = self.peek().into();
+            self.expect(TokenKind::Ident)?;
+            Some(superclass)
+        } else {
+            None
+        };
+        self.expect(TokenKind::LeftBrace)?;
+        let mut methods = Vec::new();
+        while !self.check(TokenKind::RightBrace) && !self.check(TokenKind::Eof) {
+            methods.push(self.function()?);
+        }
+        self.expect(TokenKind::RightBrace)?;
+        Ok(ast::Statement::Class(ast::ClassDecl {
+            name,
+            superclass,
+            methods,
+        }))
     }
 
     fn var_declaration(&mut self) -> ParseResult<ast::Statement> {
         self.advance();
         // TODO: Refactor borrowing to unify the two lines below.
-        let name = self.peek().lexeme.to_owned();
+        let name = self.peek().into();
         self.expect(TokenKind::Ident)?;
         let expr = if self.check(TokenKind::Equal) {
+            self.advance();
             Some(self.expression()?)
         } else {
             None
@@ -219,4 +534,264 @@ fn tests() {
 
     let s = parse_expr("(((0)))");
     assert_eq!(s.to_string(), "0");
+
+    let s = parse_expr("!true == (\"a b\" != nil) or false");
+    assert_eq!(s.to_string(), "(or (== (! true) (!= \"a b\" nil)) false)");
+
+    let s = parse_expr("a or b and c or d and e");
+    assert_eq!(s.to_string(), "(or (or a (and b c)) (and d e))");
+
+    let s = parse_expr("a and b == c or d");
+    assert_eq!(s.to_string(), "(or (and a (== b c)) d)");
+
+    let s = parse_expr("-f(1, g(2) + 3)(x)");
+    assert_eq!(s.to_string(), "(- (call (call f 1 (+ (call g 2) 3)) x))");
+
+    let s = parse_expr("this.left.check() - a.b");
+    assert_eq!(s.to_string(), "(- (call (. (. this left) check)) (. a b))");
+
+    let s = parse_expr("super.method(1) + super.field");
+    assert_eq!(
+        s.to_string(),
+        "(+ (call (. super method) 1) (. super field))"
+    );
+
+    let s = parse_expr("a.b.c = -d.e");
+    assert_eq!(s.to_string(), "(= (. (. a b) c) (- (. d e)))");
+
+    let s = parse_expr("a = b = 1 + 2");
+    assert_eq!(s.to_string(), "(= a (= b (+ 1 2)))");
+
+    let mut parser = Parser::new("a + b = 1");
+    assert!(matches!(
+        parser.expression(),
+        Err(Error::InvalidAssignmentTarget { line: 1, column: 7 })
+    ));
+
+    let mut parser = Parser::new("var answer = 42; var empty;");
+    let program = parser.statements().expect("failed to parse input");
+    assert!(matches!(
+        &program[..],
+        [
+            ast::Statement::VarDeclaration(
+                a,
+                Some(ast::ExpressionStmt::Number(
+                    42.0,
+                    ast::Position {
+                        line: 1,
+                        column: 14
+                    }
+                ))
+            ),
+            ast::Statement::VarDeclaration(b, None),
+        ] if a.name == "answer" && b.name == "empty"
+    ));
+
+    let mut parser = Parser::new("{ var a = 1; { print a; } }");
+    let program = parser.statements().expect("failed to parse input");
+    let [ast::Statement::Block(outer)] = &program[..] else {
+        panic!("expected a single block");
+    };
+    assert!(matches!(
+        &outer[..],
+        [ast::Statement::VarDeclaration(..), ast::Statement::Block(inner)] if inner.len() == 1
+    ));
+
+    let mut parser = Parser::new("if (a) print 1; else if (b) print 2; else print 3;");
+    let program = parser.statements().expect("failed to parse input");
+    let [ast::Statement::If(outer)] = &program[..] else {
+        panic!("expected a single if statement");
+    };
+    assert_eq!(outer.condition.to_string(), "a");
+    assert!(matches!(*outer.then_branch, ast::Statement::Print(_)));
+    assert!(matches!(
+        outer.else_branch.as_deref(),
+        Some(ast::Statement::If(ast::IfStmt {
+            else_branch: Some(_),
+            ..
+        }))
+    ));
+
+    let mut parser = Parser::new("for (;;) {} for (var i = 0; i; i = i - 1) print i;");
+    let program = parser.statements().expect("failed to parse input");
+    assert!(matches!(
+        &program[..],
+        [
+            ast::Statement::For(ast::ForStmt {
+                initializer: None,
+                condition: None,
+                increment: None,
+                ..
+            }),
+            ast::Statement::For(ast::ForStmt {
+                initializer: Some(_),
+                condition: Some(_),
+                increment: Some(_),
+                ..
+            }),
+        ]
+    ));
+
+    let mut parser = Parser::new("while (a) a = a - 1;");
+    let program = parser.statements().expect("failed to parse input");
+    let [ast::Statement::While(stmt)] = &program[..] else {
+        panic!("expected a single while statement");
+    };
+    assert_eq!(stmt.condition.to_string(), "a");
+
+    let mut parser = Parser::new("fun add(a, b) { return a + b; } fun nothing() { return; }");
+    let program = parser.statements().expect("failed to parse input");
+    let [
+        ast::Statement::Function(add),
+        ast::Statement::Function(nothing),
+    ] = &program[..]
+    else {
+        panic!("expected two function declarations");
+    };
+    assert_eq!(add.name.name, "add");
+    assert_eq!(add.params.len(), 2);
+    assert!(matches!(
+        &add.body[..],
+        [ast::Statement::Return(ast::ReturnStmt {
+            value: Some(_),
+            line: 1,
+            column: 17
+        })]
+    ));
+    assert!(nothing.params.is_empty());
+    assert!(matches!(
+        &nothing.body[..],
+        [ast::Statement::Return(ast::ReturnStmt { value: None, .. })]
+    ));
+
+    let input = format!("f({});", vec!["a"; 256].join(", "));
+    let mut parser = Parser::new(&input);
+    assert!(matches!(
+        &parser.statements().err().unwrap()[..],
+        [Error::TooManyArguments { .. }]
+    ));
+
+    let input = include_str!("../programs/binary_trees.lox");
+    let program = Parser::new(input)
+        .statements()
+        .expect("failed to parse input");
+    let ast::Statement::Class(tree) = &program[0] else {
+        panic!("expected a class declaration");
+    };
+    assert_eq!(tree.name.name, "Tree");
+    let methods: Vec<_> = tree.methods.iter().map(|m| m.name.name.as_str()).collect();
+    assert_eq!(methods, ["init", "check"]);
+    assert!(tree.superclass.is_none());
+
+    let mut parser = Parser::new("class B < A {}");
+    let program = parser.statements().expect("failed to parse input");
+    assert!(matches!(
+        &program[..],
+        [ast::Statement::Class(ast::ClassDecl {
+            superclass: Some(superclass),
+            ..
+        })] if superclass.name == "A"
+    ));
+
+    let mut parser = Parser::new("super;");
+    assert!(matches!(
+        &parser.statements().err().unwrap()[..],
+        [Error::UnexpectedToken {
+            expected: Some(TokenKind::Dot),
+            ..
+        }]
+    ));
+
+    let mut parser = Parser::new("{ var a = 1;");
+    assert!(matches!(
+        &parser.statements().err().unwrap()[..],
+        [Error::UnexpectedToken {
+            expected: Some(TokenKind::RightBrace),
+            found: TokenKind::Eof,
+            ..
+        }]
+    ));
+
+    // Every malformed statement is reported, lexer errors included, in
+    // source order and without cascading errors after each one.
+    let input =
+        "var = 1;\nprint 1 +;\nvar a = @;\nfun f() { return (; }\nprint \"ok\" $ #;\nclass { }";
+    let mut parser = Parser::new(input);
+    let errors = parser.statements().err().unwrap();
+    assert!(matches!(
+        &errors[..],
+        [
+            Error::UnexpectedToken {
+                expected: Some(TokenKind::Ident),
+                line: 1,
+                ..
+            },
+            Error::UnexpectedToken {
+                expected: None,
+                found: TokenKind::Semicolon,
+                line: 2,
+                ..
+            },
+            Error::UnexpectedChar {
+                ch: '@',
+                line: 3,
+                ..
+            },
+            Error::UnexpectedToken {
+                found: TokenKind::Semicolon,
+                line: 4,
+                ..
+            },
+            Error::UnexpectedChar {
+                ch: '$',
+                line: 5,
+                ..
+            },
+            Error::UnexpectedChar {
+                ch: '#',
+                line: 5,
+                ..
+            },
+            Error::UnexpectedToken {
+                expected: Some(TokenKind::Ident),
+                line: 6,
+                ..
+            },
+        ]
+    ));
+
+    // A statement keyword that caused an error starts the next declaration.
+    let mut parser = Parser::new("print 1 +\nvar a = ;\nfun\nclass A {}");
+    assert!(matches!(
+        &parser.statements().err().unwrap()[..],
+        [
+            Error::UnexpectedToken {
+                found: TokenKind::Var,
+                line: 2,
+                column: 1,
+                ..
+            },
+            Error::UnexpectedToken {
+                found: TokenKind::Semicolon,
+                line: 2,
+                column: 9,
+                ..
+            },
+            Error::UnexpectedToken {
+                found: TokenKind::Class,
+                line: 4,
+                ..
+            },
+        ]
+    ));
+
+    // Statements after an error inside a block are still parsed.
+    let mut parser = Parser::new("{ print; var a = 1; }\n\"unterminated");
+    assert!(matches!(
+        &parser.statements().err().unwrap()[..],
+        [
+            Error::UnexpectedToken { line: 1, .. },
+            Error::UnterminatedString { line: 2, .. }
+        ]
+    ));
 }
@@ -0,0 +1,178 @@
+use crate::{ErrorFormat, report};
+use loxemu::{Lexer, ast, lex::TokenKind, vm::VM};
+use rustyline::{DefaultEditor, error::ReadlineError};
+use std::path::{Path, PathBuf};
+
+const HISTORY_FILE: &str = ".loxemu_history";
+
+const HELP: &str = "\
+:disasm        Show the bytecode of the last input
+:disasm <name> Show the bytecode of a global function
+:stack         Show the value stack where the last input failed
+:reset         Forget all globals and start over
+:help          Show this message
+:quit          Leave the REPL";
+
+/// Reads and runs Lox line by line until end of input.
+pub fn run() -> rustyline::Result<()> {
+    let mut editor = DefaultEditor::new()?;
+    let history = std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+    if let Some(path) = &history {
+        // There is no history yet on the first run.
+        let _ = editor.load_history(path);
+    }
+
+    let mut session = Session::new();
+    let mut input = String::new();
+    loop {
+        let prompt = if input.is_empty() { "> " } else { "... " };
+        match editor.readline(prompt) {
+            Ok(line) if input.is_empty() && line.trim_start().starts_with(':') => {
+                editor.add_history_entry(line.as_str())?;
+                if !session.command(line.trim()) {
+                    break;
+                }
+            }
+            Ok(line) => {
+                input.push_str(&line);
+                input.push('\n');
+                if is_incomplete(&input) {
+                    continue;
+                }
+                editor.add_history_entry(input.trim_end())?;
+                session.eval(&input);
+                input.clear();
+            }
+            // Ctrl-C drops the pending input, Ctrl-D leaves.
+            Err(ReadlineError::Interrupted) => input.clear(),
+            Err(ReadlineError::Eof) => break,
+            Err(error) => return Err(error),
+        }
+    }
+
+    if let Some(path) = &history {
+        editor.save_history(path)?;
+    }
+    Ok(())
+}
+
+/// A VM whose globals outlive each input.
+struct Session {
+    vm: VM,
+    last_disassembly: Option<String>,
+}
+
+impl Session {
+    fn new() -> Self {
+        Self {
+            vm: VM::from_program(&mut []).expect("an empty program compiles"),
+            last_disassembly: None,
+        }
+    }
+
+    fn eval(&mut self, source: &str) {
+        let filename = Path::new("<repl>");
+        let mut program = match loxemu::Parser::new(source).statements() {
+            Ok(program) => echo_expressions(program),
+            Err(errors) => return report(&errors, source, filename, ErrorFormat::Human),
+        };
+        if let Err(error) = self.vm.load_program(&mut program) {
+            return report(&[error], source, filename, ErrorFormat::Human);
+        }
+        self.last_disassembly = Some(format!("{:?}", self.vm.script().chunk));
+        if let Err(error) = self.vm.interpret() {
+            report(&[error], source, filename, ErrorFormat::Human);
+        }
+    }
+
+    /// Runs a meta-command. Returns false once the session should end.
+    fn command(&mut self, line: &str) -> bool {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some(":disasm"), None) => match &self.last_disassembly {
+                Some(disassembly) => print!("{disassembly}"),
+                None => println!("Nothing has been compiled yet"),
+            },
+            (Some(":disasm"), Some(name)) => match self.vm.global_function(name) {
+                Some(function) => print!("{:?}", function.chunk),
+                None if self.vm.global_to_string(name).is_some() => {
+                    println!("'{name}' is not a Lox function")
+                }
+                None => println!("Undefined variable '{name}'"),
+            },
+            (Some(":stack"), None) => match self.vm.failed_stack() {
+                [] => println!("The last input did not fail"),
+                stack => {
+                    let slots: Vec<String> =
+                        stack.iter().map(|value| format!("[ {value} ]")).collect();
+                    println!("{}", slots.join(""));
+                }
+            },
+            (Some(":reset"), None) => *self = Session::new(),
+            (Some(":help"), None) => println!("{HELP}"),
+            (Some(":quit"), None) => return false,
+            _ => println!("Unknown command '{line}', try :help"),
+        }
+        true
+    }
+}
+
+/// Whether the input has unclosed braces, so more lines should follow.
+fn is_incomplete(source: &str) -> bool {
+    let mut depth = 0;
+    for token in Lexer::new(source).flatten() {
+        match token.kind {
+            TokenKind::LeftBrace => depth += 1,
+            TokenKind::RightBrace => depth -= 1,
+            _ => {}
+        }
+    }
+    depth > 0
+}
+
+/// Prints the value of every top-level expression statement.
+fn echo_expressions(program: Vec<ast::Statement>) -> Vec<ast::Statement> {
+    program
+        .into_iter()
+        .map(|statement| match statement {
+            ast::Statement::Expression(expr) => ast::Statement::Print(ast::PrintStmt { expr }),
+            statement => statement,
+        })
+        .collect()
+}
+
+#[test]
+fn tests() {
+    assert!(!is_incomplete("print 1;\n"));
+    assert!(is_incomplete("fun f() {\n"));
+    assert!(is_incomplete("class A {\n  m() {\n  }\n"));
+    assert!(!is_incomplete("class A {\n  m() {\n  }\n}\n"));
+    // Braces in strings and comments don't count.
+    assert!(!is_incomplete("print \"{\"; // {\n"));
+
+    let program = loxemu::Parser::new("1 + 2; var a = 1; { a; }")
+        .statements()
+        .unwrap();
+    assert!(matches!(
+        &echo_expressions(program)[..],
+        [
+            ast::Statement::Print(_),
+            ast::Statement::VarDeclaration(..),
+            ast::Statement::Block(block),
+        ] if matches!(&block[..], [ast::Statement::Expression(_)])
+    ));
+
+    let mut session = Session::new();
+    session.eval("var a = 1;\n");
+    session.eval("a = a +;\n");
+    session.eval("print a + nil;\n");
+    assert_eq!(session.vm.failed_stack(), ["<script>", "1", "nil"]);
+    session.eval("fun f() { return a + 1; }\n");
+    assert!(session.command(":disasm f"));
+    session.eval("var b = f();\n");
+    assert!(session.vm.failed_stack().is_empty());
+    assert_eq!(session.vm.global_to_string("b").as_deref(), Some("2"));
+    assert!(session.command(":reset"));
+    assert_eq!(session.vm.global_to_string("a"), None);
+    assert!(!session.command(":quit"));
+}
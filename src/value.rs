@@ -1,15 +1,41 @@
 use crate::error::Error;
+use crate::gc::{Gc, Heap, Trace, Tracer};
+use crate::object::{BoundMethod, Class, Closure, Function, Instance, NativeFn};
+use std::cell::RefCell;
 use std::fmt::Display;
 
-#[derive(Debug, PartialEq, PartialOrd, Clone)]
-pub enum Value {
+#[derive(Debug, Clone)]
+pub(crate) enum Value {
     Nil,
     Bool(bool),
     Number(f64),
-    String(String),
-    // TODO: Add nested types to enum variants.
-    // Function,
-    // Closure,
+    String(Gc<String>),
+    Function(Gc<Function>),
+    Closure(Gc<Closure>),
+    Class(Gc<RefCell<Class>>),
+    Instance(Gc<RefCell<Instance>>),
+    BoundMethod(Gc<BoundMethod>),
+    NativeFn(Gc<NativeFn>),
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Nil, Value::Nil) => true,
+            (Value::Bool(lhs), Value::Bool(rhs)) => lhs == rhs,
+            (Value::Number(lhs), Value::Number(rhs)) => lhs == rhs,
+            // Strings are interned, so equal strings are the same object.
+            (Value::String(lhs), Value::String(rhs)) => Gc::ptr_eq(lhs, rhs),
+            // Objects are equal only when they are the same object.
+            (Value::Function(lhs), Value::Function(rhs)) => Gc::ptr_eq(lhs, rhs),
+            (Value::Closure(lhs), Value::Closure(rhs)) => Gc::ptr_eq(lhs, rhs),
+            (Value::Class(lhs), Value::Class(rhs)) => Gc::ptr_eq(lhs, rhs),
+            (Value::Instance(lhs), Value::Instance(rhs)) => Gc::ptr_eq(lhs, rhs),
+            (Value::BoundMethod(lhs), Value::BoundMethod(rhs)) => Gc::ptr_eq(lhs, rhs),
+            (Value::NativeFn(lhs), Value::NativeFn(rhs)) => Gc::ptr_eq(lhs, rhs),
+            (_, _) => false,
+        }
+    }
 }
 
 impl Display for Value {
@@ -19,6 +45,27 @@ impl Display for Value {
             Value::Bool(b) => write!(f, "{b}"),
             Value::Number(x) => write!(f, "{x}"),
             Value::String(s) => write!(f, "{s}"),
+            Value::Function(function) => write!(f, "{function}"),
+            Value::Closure(closure) => write!(f, "{closure}"),
+            Value::Class(class) => write!(f, "{}", class.borrow()),
+            Value::Instance(instance) => write!(f, "{}", instance.borrow()),
+            Value::BoundMethod(method) => write!(f, "{method}"),
+            Value::NativeFn(native) => write!(f, "{native}"),
+        }
+    }
+}
+
+impl Trace for Value {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Value::Nil | Value::Bool(_) | Value::Number(_) => {}
+            Value::String(s) => tracer.mark(*s),
+            Value::Function(function) => tracer.mark(*function),
+            Value::Closure(closure) => tracer.mark(*closure),
+            Value::Class(class) => tracer.mark(*class),
+            Value::Instance(instance) => tracer.mark(*instance),
+            Value::BoundMethod(method) => tracer.mark(*method),
+            Value::NativeFn(native) => tracer.mark(*native),
         }
     }
 }
@@ -28,11 +75,35 @@ impl Value {
         Self::Nil
     }
 
-    pub fn checked_add(self, rhs: Self) -> Result<Self, Error> {
+    pub fn string(heap: &Heap, s: impl AsRef<str> + Into<String>) -> Self {
+        Self::String(heap.intern(s))
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Bool(_) => "bool",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Function(_) | Value::Closure(_) | Value::BoundMethod(_) | Value::NativeFn(_) => {
+                "function"
+            }
+            Value::Class(_) => "class",
+            Value::Instance(_) => "instance",
+        }
+    }
+
+    /// Lox treats `nil` and `false` as falsey and every other value as truthy.
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    /// Concatenating strings allocates the result on `heap`.
+    pub fn checked_add(self, rhs: Self, heap: &Heap) -> Result<Self, Error> {
         match (self, rhs) {
             (Value::Number(lhs), Value::Number(rhs)) => Ok(Value::Number(lhs + rhs)),
             (Value::String(lhs), Value::String(rhs)) => {
-                Ok(Value::String(format!("{}{}", lhs, rhs)))
+                Ok(Value::string(heap, [lhs.as_str(), rhs.as_str()].concat()))
             }
             (_, _) => Err(Error::type_error("addition", "mix of number ans string")),
         }
@@ -52,13 +123,31 @@ impl Value {
         Err(Error::type_error("multiplication", "non-number"))
     }
 
+    /// Dividing by zero is an error rather than infinity or NaN.
     pub fn checked_div(self, rhs: Self) -> Result<Self, Error> {
         if let (Value::Number(lhs), Value::Number(rhs)) = (self, rhs) {
+            if rhs == 0.0 {
+                return Err(Error::division_by_zero());
+            }
             return Ok(Value::Number(lhs / rhs));
         }
         Err(Error::type_error("division", "non-number"))
     }
 
+    pub fn checked_greater(self, rhs: Self) -> Result<Self, Error> {
+        if let (Value::Number(lhs), Value::Number(rhs)) = (self, rhs) {
+            return Ok(Value::Bool(lhs > rhs));
+        }
+        Err(Error::type_error("comparison", "non-number"))
+    }
+
+    pub fn checked_less(self, rhs: Self) -> Result<Self, Error> {
+        if let (Value::Number(lhs), Value::Number(rhs)) = (self, rhs) {
+            return Ok(Value::Bool(lhs < rhs));
+        }
+        Err(Error::type_error("comparison", "non-number"))
+    }
+
     pub fn checked_neg(self) -> Result<Self, Error> {
         if let Value::Number(lhs) = self {
             return Ok(Value::Number(-lhs));
@@ -0,0 +1,497 @@
+use crate::Value;
+use crate::error::Error;
+use crate::object::Function;
+use crate::vm::OpCode;
+
+/// Checks that the bytecode of `script` and every function nested in it can
+/// run without the VM reading out of bounds: opcodes are valid, operands fit
+/// in the code, constants and slots exist and have the right type, jumps land
+/// on instructions, and every path through the code reaches each instruction
+/// with the same stack depth and never pops past the frame or runs off the end.
+///
+/// The compiler's output always passes, so this only needs to run on bytecode
+/// from elsewhere, such as `.loxc` files.
+pub fn verify(script: &Function) -> Result<(), Error> {
+    // The VM starts the script with an empty stack and no captured variables.
+    if script.arity != 0 || script.upvalue_count != 0 {
+        return Err(Error::invalid_bytecode(
+            script,
+            0,
+            "the script can't take parameters or capture variables",
+        ));
+    }
+    let mut pending = vec![script];
+    while let Some(function) = pending.pop() {
+        verify_function(function)?;
+        pending.extend(
+            function
+                .chunk
+                .constants
+                .iter()
+                .filter_map(|constant| match constant {
+                    Value::Function(function) => Some(&**function),
+                    _ => None,
+                }),
+        );
+    }
+    Ok(())
+}
+
+/// How an instruction moves the stack and where execution goes next.
+struct Effect {
+    /// Values the instruction needs on the stack.
+    needs: usize,
+    pops: usize,
+    pushes: usize,
+    /// Whether execution can continue with the next instruction.
+    falls_through: bool,
+    jump: Option<usize>,
+}
+
+impl Effect {
+    fn new(needs: usize, pops: usize, pushes: usize) -> Self {
+        Self {
+            needs,
+            pops,
+            pushes,
+            falls_through: true,
+            jump: None,
+        }
+    }
+}
+
+fn verify_function(function: &Function) -> Result<(), Error> {
+    let chunk = &function.chunk;
+    let error = |offset, reason: String| Error::invalid_bytecode(function, offset, reason);
+    if function.arity > u8::MAX as usize {
+        return Err(error(0, format!("arity {} is above 255", function.arity)));
+    }
+    if function.upvalue_count > u8::MAX as usize + 1 {
+        return Err(error(
+            0,
+            format!("{} upvalues is more than 256", function.upvalue_count),
+        ));
+    }
+    if chunk.code.is_empty() {
+        return Err(error(0, "the code is empty".into()));
+    }
+
+    // Decode the code front to back, which also finds where instructions start.
+    let mut lengths = vec![0; chunk.code.len()];
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let len = instruction_len(function, offset).map_err(|reason| error(offset, reason))?;
+        lengths[offset] = len;
+        offset += len;
+    }
+
+    // Walk every path from the entry, where the stack holds the callee and
+    // its arguments, tracking the stack depth at each instruction.
+    let mut depths: Vec<Option<usize>> = vec![None; chunk.code.len()];
+    depths[0] = Some(function.arity + 1);
+    let mut pending = vec![0];
+    while let Some(offset) = pending.pop() {
+        let depth = depths[offset].expect("pending instructions have a depth");
+        let effect = effect(function, offset, depth).map_err(|reason| error(offset, reason))?;
+        // Slot 0 holds the callee, and nothing may pop it or what is below.
+        if depth < effect.needs + 1 {
+            return Err(error(
+                offset,
+                format!(
+                    "stack underflow at {}: needs {}, has {}",
+                    instruction_name(chunk.code[offset]),
+                    effect.needs,
+                    depth - 1
+                ),
+            ));
+        }
+        let depth = depth - effect.pops + effect.pushes;
+
+        let next = offset + lengths[offset];
+        let successors = effect
+            .falls_through
+            .then_some(next)
+            .into_iter()
+            .chain(effect.jump);
+        for successor in successors {
+            if successor >= chunk.code.len() {
+                return Err(error(
+                    offset,
+                    "execution runs past the end of the code".into(),
+                ));
+            }
+            if lengths[successor] == 0 {
+                return Err(error(
+                    offset,
+                    format!("jump to offset {successor}, which is inside an instruction"),
+                ));
+            }
+            match depths[successor] {
+                None => {
+                    depths[successor] = Some(depth);
+                    pending.push(successor);
+                }
+                Some(known) if known != depth => {
+                    return Err(error(
+                        successor,
+                        format!("reached with stack depths {known} and {depth}"),
+                    ));
+                }
+                Some(_) => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+fn instruction_name(byte: u8) -> String {
+    OpCode::try_from(byte).map_or_else(|_| format!("{byte:#x}"), |op| op.to_string())
+}
+
+/// The length of the instruction at `offset`, after checking its operands
+/// against the code and the constant pool.
+fn instruction_len(function: &Function, offset: usize) -> Result<usize, String> {
+    let chunk = &function.chunk;
+    let byte = chunk.code[offset];
+    let instruction =
+        OpCode::try_from(byte).map_err(|_| format!("invalid instruction {byte:#x}"))?;
+    let operand = |i: usize| {
+        chunk
+            .code
+            .get(offset + i)
+            .copied()
+            .ok_or_else(|| format!("{instruction} is cut off by the end of the code"))
+    };
+    let constant = |id: usize| {
+        chunk.constants.get(id).ok_or_else(|| {
+            format!(
+                "{instruction} uses constant {id} but the chunk has {}",
+                chunk.constants.len()
+            )
+        })
+    };
+
+    // Reads the constant index operand, one byte or three for long forms.
+    let constant_id = || {
+        let width = if instruction.is_long() { 3 } else { 1 };
+        operand(width)?;
+        Ok::<_, String>(chunk.constant_operand(instruction, offset))
+    };
+
+    match instruction {
+        OpCode::Constant | OpCode::ConstantLong => {
+            let (id, width) = constant_id()?;
+            constant(id)?;
+            Ok(1 + width)
+        }
+        OpCode::DefineGlobal
+        | OpCode::DefineGlobalLong
+        | OpCode::GetGlobal
+        | OpCode::GetGlobalLong
+        | OpCode::SetGlobal
+        | OpCode::SetGlobalLong
+        | OpCode::Class
+        | OpCode::ClassLong
+        | OpCode::GetProperty
+        | OpCode::GetPropertyLong
+        | OpCode::SetProperty
+        | OpCode::SetPropertyLong
+        | OpCode::Method
+        | OpCode::MethodLong
+        | OpCode::GetSuper
+        | OpCode::GetSuperLong => {
+            let (id, width) = constant_id()?;
+            match constant(id)? {
+                Value::String(_) => Ok(1 + width),
+                other => Err(format!(
+                    "{instruction} needs a string name but got a {}",
+                    other.type_name()
+                )),
+            }
+        }
+        OpCode::Closure | OpCode::ClosureLong => {
+            let (id, width) = constant_id()?;
+            let Value::Function(closed) = constant(id)? else {
+                return Err(format!("{instruction} needs a function constant"));
+            };
+            for i in 0..closed.upvalue_count {
+                let is_local = operand(1 + width + 2 * i)?;
+                let index = operand(2 + width + 2 * i)? as usize;
+                match is_local {
+                    0 if index >= function.upvalue_count => {
+                        return Err(format!(
+                            "{instruction} captures upvalue {index} but the function only captures {}",
+                            function.upvalue_count
+                        ));
+                    }
+                    0 | 1 => {}
+                    flag => return Err(format!("{instruction} has invalid capture flag {flag}")),
+                }
+            }
+            Ok(1 + width + 2 * closed.upvalue_count)
+        }
+        OpCode::GetUpvalue | OpCode::SetUpvalue => {
+            let index = operand(1)? as usize;
+            if index >= function.upvalue_count {
+                return Err(format!(
+                    "{instruction} uses upvalue {index} but the function only captures {}",
+                    function.upvalue_count
+                ));
+            }
+            Ok(2)
+        }
+        OpCode::GetLocal | OpCode::SetLocal | OpCode::Call => {
+            operand(1)?;
+            Ok(2)
+        }
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+            operand(2)?;
+            Ok(3)
+        }
+        OpCode::OpAdd
+        | OpCode::OpSubtract
+        | OpCode::OpMultiply
+        | OpCode::OpDivide
+        | OpCode::OpNegate
+        | OpCode::Not
+        | OpCode::Equal
+        | OpCode::Greater
+        | OpCode::Less
+        | OpCode::OpPop
+        | OpCode::CloseUpvalue
+        | OpCode::Inherit
+        | OpCode::Print
+        | OpCode::Return => Ok(1),
+    }
+}
+
+/// The stack effect of the already decoded instruction at `offset`, which
+/// runs with `depth` values in its frame.
+fn effect(function: &Function, offset: usize, depth: usize) -> Result<Effect, String> {
+    let chunk = &function.chunk;
+    let instruction = OpCode::try_from(chunk.code[offset]).expect("decoded instruction");
+    let operand = chunk.code.get(offset + 1).copied().unwrap_or_default() as usize;
+    let effect = match instruction {
+        OpCode::Constant
+        | OpCode::ConstantLong
+        | OpCode::GetGlobal
+        | OpCode::GetGlobalLong
+        | OpCode::GetUpvalue
+        | OpCode::Class
+        | OpCode::ClassLong => Effect::new(0, 0, 1),
+        OpCode::OpAdd
+        | OpCode::OpSubtract
+        | OpCode::OpMultiply
+        | OpCode::OpDivide
+        | OpCode::Equal
+        | OpCode::Greater
+        | OpCode::Less
+        | OpCode::SetProperty
+        | OpCode::SetPropertyLong
+        | OpCode::GetSuper
+        | OpCode::GetSuperLong => Effect::new(2, 2, 1),
+        // The class stays on the stack below the method or subclass.
+        OpCode::Method | OpCode::MethodLong | OpCode::Inherit => Effect::new(2, 1, 0),
+        OpCode::OpNegate | OpCode::Not | OpCode::GetProperty | OpCode::GetPropertyLong => {
+            Effect::new(1, 1, 1)
+        }
+        OpCode::SetGlobal | OpCode::SetGlobalLong | OpCode::SetUpvalue => Effect::new(1, 0, 0),
+        OpCode::OpPop
+        | OpCode::DefineGlobal
+        | OpCode::DefineGlobalLong
+        | OpCode::CloseUpvalue
+        | OpCode::Print => Effect::new(1, 1, 0),
+        OpCode::GetLocal | OpCode::SetLocal => {
+            if operand >= depth {
+                return Err(format!(
+                    "{instruction} uses slot {operand} but the frame only has slots 0 to {}",
+                    depth - 1
+                ));
+            }
+            match instruction {
+                OpCode::GetLocal => Effect::new(0, 0, 1),
+                _ => Effect::new(1, 0, 0),
+            }
+        }
+        OpCode::Call => Effect::new(operand + 1, operand + 1, 1),
+        OpCode::Closure | OpCode::ClosureLong => {
+            let (id, width) = chunk.constant_operand(instruction, offset);
+            let Value::Function(closed) = &chunk.constants[id] else {
+                unreachable!("decoded closures have a function constant");
+            };
+            for i in 0..closed.upvalue_count {
+                let is_local = chunk.code[offset + 1 + width + 2 * i] == 1;
+                let index = chunk.code[offset + 2 + width + 2 * i] as usize;
+                if is_local && index >= depth {
+                    return Err(format!(
+                        "{instruction} captures slot {index} but the frame only has slots 0 to {}",
+                        depth - 1
+                    ));
+                }
+            }
+            Effect::new(0, 0, 1)
+        }
+        OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+            let target = chunk
+                .jump_target(offset)
+                .ok_or_else(|| format!("{instruction} jumps before the start of the code"))?;
+            Effect {
+                needs: usize::from(instruction == OpCode::JumpIfFalse),
+                pops: 0,
+                pushes: 0,
+                falls_through: instruction == OpCode::JumpIfFalse,
+                jump: Some(target),
+            }
+        }
+        OpCode::Return => Effect {
+            falls_through: false,
+            ..Effect::new(1, 1, 0)
+        },
+    };
+    Ok(effect)
+}
+
+#[test]
+fn tests() {
+    use crate::gc::Heap;
+    use crate::vm::Chunk;
+
+    {
+        // Everything the compiler produces passes.
+        let heap = Heap::new();
+        for source in [
+            include_str!("../programs/binary_trees.lox"),
+            "
+            fun outer(a) {
+              var b = a;
+              fun inner() { a = a + b; return a; }
+              for (var i = 0; i < 3 and !false or nil; i = i + 1) {
+                var c = i;
+                fun capture() { return c; }
+                if (c == 1) return capture; else print c;
+              }
+              return inner;
+            }
+            class A { init(x) { this.x = x; return; } get() { return this.x; } }
+            class B < A { get() { return super.get() - -1; } }
+            print outer(1)();
+            print B(2).get() / 3 * 4 > 5;
+            ",
+        ] {
+            let mut program = crate::Parser::new(source).statements().unwrap();
+            let script = crate::compiler::compile(&mut program, &heap).unwrap();
+            verify(&script).unwrap();
+        }
+    }
+
+    let check = |code: &[u8], constants: Vec<Value>| {
+        let mut chunk = Chunk::new("test");
+        for &byte in code {
+            chunk.emit(byte, 1, 1);
+        }
+        chunk.constants = constants;
+        match verify(&Function::script(chunk)) {
+            Ok(()) => String::new(),
+            Err(error) => error.to_string(),
+        }
+    };
+    let op = |op: OpCode| u8::from(op);
+    let heap = Heap::new();
+    let name = Value::string(&heap, "a");
+
+    let mut script = Function::script(Chunk::new("test"));
+    script.upvalue_count = 1;
+    assert!(
+        verify(&script)
+            .unwrap_err()
+            .to_string()
+            .contains("capture variables")
+    );
+    assert_eq!(
+        check(
+            &[op(OpCode::Constant), 0, op(OpCode::Return)],
+            vec![Value::Nil]
+        ),
+        ""
+    );
+    assert_eq!(
+        check(&[0xee], vec![]),
+        "error[B0005]: Invalid bytecode in <script> at offset 0: invalid instruction 0xee"
+    );
+    assert!(
+        check(
+            &[op(OpCode::Constant), 1, op(OpCode::Return)],
+            vec![Value::Nil]
+        )
+        .ends_with("Const uses constant 1 but the chunk has 1")
+    );
+    assert!(
+        check(&[op(OpCode::Constant)], vec![]).ends_with("Const is cut off by the end of the code")
+    );
+    assert!(
+        check(
+            &[op(OpCode::GetGlobal), 0, op(OpCode::Return)],
+            vec![Value::Nil]
+        )
+        .ends_with("GetGlobal needs a string name but got a nil")
+    );
+    assert!(
+        check(&[op(OpCode::GetUpvalue), 0, op(OpCode::Return)], vec![])
+            .ends_with("GetUpvalue uses upvalue 0 but the function only captures 0")
+    );
+    assert!(
+        check(&[op(OpCode::GetLocal), 1, op(OpCode::Return)], vec![])
+            .ends_with("GetLocal uses slot 1 but the frame only has slots 0 to 0")
+    );
+    assert!(
+        check(&[op(OpCode::Return)], vec![]).ends_with("stack underflow at Return: needs 1, has 0")
+    );
+    assert!(
+        check(&[op(OpCode::GetGlobal), 0], vec![name.clone()])
+            .ends_with("at offset 0: execution runs past the end of the code")
+    );
+    // The jump lands on the operand of `Const`.
+    assert!(
+        check(
+            &[
+                op(OpCode::Jump),
+                0,
+                1,
+                op(OpCode::Constant),
+                0,
+                op(OpCode::Return)
+            ],
+            vec![Value::Nil]
+        )
+        .ends_with("jump to offset 4, which is inside an instruction")
+    );
+    assert!(
+        check(&[op(OpCode::Loop), 0, 4], vec![])
+            .ends_with("Loop jumps before the start of the code")
+    );
+    // One path pushes a value before the merge and the other does not.
+    assert!(
+        check(
+            &[
+                op(OpCode::GetGlobal),
+                0,
+                op(OpCode::JumpIfFalse),
+                0,
+                2,
+                op(OpCode::GetGlobal),
+                0,
+                op(OpCode::Return),
+            ],
+            vec![name.clone()]
+        )
+        .ends_with("at offset 7: reached with stack depths 2 and 3")
+    );
+    // A loop that grows the stack on every iteration.
+    assert!(
+        check(
+            &[op(OpCode::GetGlobal), 0, op(OpCode::Loop), 0, 5],
+            vec![name]
+        )
+        .contains("reached with stack depths")
+    );
+}
@@ -1,51 +1,199 @@
 use crate::Value;
-use crate::error::Error;
+use crate::error::{Error, TraceFrame};
+use crate::gc::{Gc, Heap, Trace};
+use crate::object::{
+    BoundMethod, Class, Closure, Function, Instance, NativeFn, NativeFunction, Upvalue,
+};
+use crate::{ast, bytecode, compiler, native};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt::Display;
 
+/// Maximum depth of nested calls before the VM reports a stack overflow.
+const FRAMES_MAX: usize = 256;
+/// Room for every frame to use all the 256 slots a one-byte operand addresses.
+const STACK_MAX: usize = FRAMES_MAX * 256;
+/// Largest constant index the 24-bit operand of `ConstantLong` can address.
+pub const MAX_LONG_CONSTANT: usize = (1 << 24) - 1;
+
 #[derive(IntoPrimitive, TryFromPrimitive, PartialEq, Eq, Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum OpCode {
     Constant,
+    ConstantLong,
     OpAdd,
     OpSubtract,
     OpMultiply,
     OpDivide,
     OpNegate,
+    Not,
+    Equal,
+    Greater,
+    Less,
     OpPop,
+    DefineGlobal,
+    GetGlobal,
+    SetGlobal,
+    GetLocal,
+    SetLocal,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    Closure,
+    GetUpvalue,
+    SetUpvalue,
+    CloseUpvalue,
+    Class,
+    GetProperty,
+    SetProperty,
+    Method,
+    Inherit,
+    GetSuper,
     Print,
     Return,
+    // Long forms of the instructions that name a constant, taking a 24-bit
+    // index like `ConstantLong`.
+    DefineGlobalLong,
+    GetGlobalLong,
+    SetGlobalLong,
+    ClosureLong,
+    ClassLong,
+    GetPropertyLong,
+    SetPropertyLong,
+    MethodLong,
+    GetSuperLong,
+}
+
+impl OpCode {
+    /// The variant taking a 24-bit constant index, for instructions that
+    /// take a one-byte one.
+    pub fn long_form(self) -> Option<OpCode> {
+        match self {
+            OpCode::Constant => Some(OpCode::ConstantLong),
+            OpCode::DefineGlobal => Some(OpCode::DefineGlobalLong),
+            OpCode::GetGlobal => Some(OpCode::GetGlobalLong),
+            OpCode::SetGlobal => Some(OpCode::SetGlobalLong),
+            OpCode::Closure => Some(OpCode::ClosureLong),
+            OpCode::Class => Some(OpCode::ClassLong),
+            OpCode::GetProperty => Some(OpCode::GetPropertyLong),
+            OpCode::SetProperty => Some(OpCode::SetPropertyLong),
+            OpCode::Method => Some(OpCode::MethodLong),
+            OpCode::GetSuper => Some(OpCode::GetSuperLong),
+            _ => None,
+        }
+    }
+
+    /// Whether the instruction's constant index takes three bytes.
+    pub fn is_long(self) -> bool {
+        matches!(
+            self,
+            OpCode::ConstantLong
+                | OpCode::DefineGlobalLong
+                | OpCode::GetGlobalLong
+                | OpCode::SetGlobalLong
+                | OpCode::ClosureLong
+                | OpCode::ClassLong
+                | OpCode::GetPropertyLong
+                | OpCode::SetPropertyLong
+                | OpCode::MethodLong
+                | OpCode::GetSuperLong
+        )
+    }
 }
 
 impl Display for OpCode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             OpCode::Constant => write!(f, "Const"),
+            OpCode::ConstantLong => write!(f, "ConstLong"),
             OpCode::Print => write!(f, "Print"),
             OpCode::Return => write!(f, "Return"),
             OpCode::OpPop => write!(f, "Pop"),
+            OpCode::DefineGlobal => write!(f, "DefGlobal"),
+            OpCode::GetGlobal => write!(f, "GetGlobal"),
+            OpCode::SetGlobal => write!(f, "SetGlobal"),
+            OpCode::GetLocal => write!(f, "GetLocal"),
+            OpCode::SetLocal => write!(f, "SetLocal"),
+            OpCode::Jump => write!(f, "Jump"),
+            OpCode::JumpIfFalse => write!(f, "JumpIfFalse"),
+            OpCode::Loop => write!(f, "Loop"),
+            OpCode::Call => write!(f, "Call"),
+            OpCode::Closure => write!(f, "Closure"),
+            OpCode::GetUpvalue => write!(f, "GetUpvalue"),
+            OpCode::SetUpvalue => write!(f, "SetUpvalue"),
+            OpCode::CloseUpvalue => write!(f, "CloseUpvalue"),
+            OpCode::Class => write!(f, "Class"),
+            OpCode::GetProperty => write!(f, "GetProperty"),
+            OpCode::SetProperty => write!(f, "SetProperty"),
+            OpCode::Method => write!(f, "Method"),
+            OpCode::Inherit => write!(f, "Inherit"),
+            OpCode::GetSuper => write!(f, "GetSuper"),
             OpCode::OpAdd => write!(f, "Add"),
             OpCode::OpSubtract => write!(f, "Sub"),
             OpCode::OpMultiply => write!(f, "Mul"),
             OpCode::OpDivide => write!(f, "Div"),
             OpCode::OpNegate => write!(f, "Neg"),
+            OpCode::Not => write!(f, "Not"),
+            OpCode::Equal => write!(f, "Equal"),
+            OpCode::Greater => write!(f, "Greater"),
+            OpCode::Less => write!(f, "Less"),
+            OpCode::DefineGlobalLong => write!(f, "DefGlobalLong"),
+            OpCode::GetGlobalLong => write!(f, "GetGlobalLong"),
+            OpCode::SetGlobalLong => write!(f, "SetGlobalLong"),
+            OpCode::ClosureLong => write!(f, "ClosureLong"),
+            OpCode::ClassLong => write!(f, "ClassLong"),
+            OpCode::GetPropertyLong => write!(f, "GetPropertyLong"),
+            OpCode::SetPropertyLong => write!(f, "SetPropertyLong"),
+            OpCode::MethodLong => write!(f, "MethodLong"),
+            OpCode::GetSuperLong => write!(f, "GetSuperLong"),
         }
     }
 }
 
+/// The source position of a run of consecutive bytes in `Chunk::code`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineRun {
+    pub line: usize,
+    pub column: usize,
+    pub count: usize,
+}
+
 pub struct Chunk {
     pub name: String,
     pub code: Vec<u8>,
-    pub constants: Vec<Value>,
-    pub variables: Vec<Value>,
+    pub(crate) constants: Vec<Value>,
+    pub(crate) variables: Vec<Value>,
+    // Run-length encoded positions, covering `code` byte for byte.
+    pub lines: Vec<LineRun>,
+    // Slots of the constants `write_constant` can reuse.
+    constant_ids: HashMap<ConstantKey, usize>,
+}
+
+/// Identifies a constant for reuse. Numbers compare by bits to keep 0 and
+/// -0 apart, as they print differently, and strings by their interned object.
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Nil,
+    Bool(bool),
+    Number(u64),
+    String(Gc<String>),
 }
 
 impl std::fmt::Debug for Chunk {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "=== {} ===", self.name)?;
         let mut i = 0;
+        let mut previous_line = None;
         while i < self.code.len() {
-            write!(f, "{:04} - ", i + 1)?;
+            let line = self.position(i).map(|(line, _)| line);
+            // Like clox, only print the line when it changes.
+            match line {
+                Some(line) if previous_line != Some(line) => write!(f, "{:04} {line:4} ", i + 1)?,
+                _ => write!(f, "{:04}    | ", i + 1)?,
+            }
+            previous_line = line;
             i += self.dissasemble_instruction(f, i)?;
         }
         Ok(())
@@ -59,19 +207,70 @@ impl Chunk {
             code: Vec::new(),
             constants: Vec::new(),
             variables: Vec::new(),
+            lines: Vec::new(),
+            constant_ids: HashMap::new(),
         }
     }
 
-    pub fn emit<T>(&mut self, val: T)
+    /// Appends a byte compiled from the source at `line` and `column`.
+    pub fn emit<T>(&mut self, val: T, line: usize, column: usize)
     where
         T: Into<u8>,
     {
         self.code.push(val.into());
+        match self.lines.last_mut() {
+            Some(run) if run.line == line && run.column == column => run.count += 1,
+            _ => self.lines.push(LineRun {
+                line,
+                column,
+                count: 1,
+            }),
+        }
+    }
+
+    /// Returns the line and column the byte at `offset` was compiled from.
+    pub fn position(&self, offset: usize) -> Option<(usize, usize)> {
+        let mut end = 0;
+        for run in &self.lines {
+            end += run.count;
+            if offset < end {
+                return Some((run.line, run.column));
+            }
+        }
+        None
+    }
+
+    /// Adds a constant to the pool, reusing the slot of an identical one.
+    pub(crate) fn write_constant(&mut self, val: impl Into<Value>) -> usize {
+        let val = val.into();
+        let key = match &val {
+            Value::Nil => Some(ConstantKey::Nil),
+            Value::Bool(b) => Some(ConstantKey::Bool(*b)),
+            Value::Number(x) => Some(ConstantKey::Number(x.to_bits())),
+            Value::String(s) => Some(ConstantKey::String(*s)),
+            // Other objects are only ever equal to themselves.
+            _ => None,
+        };
+        if let Some(id) = key.as_ref().and_then(|key| self.constant_ids.get(key)) {
+            return *id;
+        }
+        self.constants.push(val);
+        let id = self.constants.len() - 1;
+        if let Some(key) = key {
+            self.constant_ids.insert(key, id);
+        }
+        id
     }
 
-    pub fn write_constant(&mut self, val: f64) -> usize {
-        self.constants.push(Value::from(val));
-        self.constants.len() - 1
+    /// The constant index operand of the instruction at `offset`, whose
+    /// opcode is `instruction`, and the operand's width in bytes.
+    pub(crate) fn constant_operand(&self, instruction: OpCode, offset: usize) -> (usize, usize) {
+        if instruction.is_long() {
+            let [a, b, c] = [1, 2, 3].map(|i| self.code[offset + i]);
+            (u32::from_be_bytes([0, a, b, c]) as usize, 3)
+        } else {
+            (self.code[offset + 1] as usize, 1)
+        }
     }
 
     pub fn write_variable(&mut self, val: f64) -> usize {
@@ -79,123 +278,733 @@ impl Chunk {
         self.variables.len() - 1
     }
 
-    fn dissasemble_instruction(
+    /// Where the jump instruction at `offset` lands, or `None` for other instructions.
+    pub fn jump_target(&self, offset: usize) -> Option<usize> {
+        let instruction = OpCode::try_from(*self.code.get(offset)?).ok()?;
+        let jump = u16::from_be_bytes([*self.code.get(offset + 1)?, *self.code.get(offset + 2)?]);
+        match instruction {
+            OpCode::Jump | OpCode::JumpIfFalse => Some(offset + 3 + jump as usize),
+            OpCode::Loop => (offset + 3).checked_sub(jump as usize),
+            _ => None,
+        }
+    }
+
+    /// Writes the instruction at `offset` without its offset and line, and
+    /// returns its length in bytes.
+    pub(crate) fn dissasemble_instruction(
         &self,
-        f: &mut std::fmt::Formatter<'_>,
+        f: &mut impl std::fmt::Write,
         offset: usize,
     ) -> Result<usize, std::fmt::Error> {
         let instruction = OpCode::try_from(self.code[offset]).map_err(|_| std::fmt::Error)?;
         match instruction {
-            OpCode::Constant => {
-                let id = self.code[offset + 1];
-                writeln!(f, "{instruction} {id} ({})", self.constants[id as usize])?;
-                return Ok(2);
+            OpCode::Constant
+            | OpCode::ConstantLong
+            | OpCode::DefineGlobal
+            | OpCode::DefineGlobalLong
+            | OpCode::GetGlobal
+            | OpCode::GetGlobalLong
+            | OpCode::SetGlobal
+            | OpCode::SetGlobalLong
+            | OpCode::Class
+            | OpCode::ClassLong
+            | OpCode::GetProperty
+            | OpCode::GetPropertyLong
+            | OpCode::SetProperty
+            | OpCode::SetPropertyLong
+            | OpCode::Method
+            | OpCode::MethodLong
+            | OpCode::GetSuper
+            | OpCode::GetSuperLong => {
+                let (id, width) = self.constant_operand(instruction, offset);
+                writeln!(f, "{instruction} {id} ({})", self.constants[id])?;
+                Ok(1 + width)
+            }
+            OpCode::Closure | OpCode::ClosureLong => {
+                let (id, width) = self.constant_operand(instruction, offset);
+                let function = &self.constants[id];
+                writeln!(f, "{instruction} {id} ({function})")?;
+                let Value::Function(function) = function else {
+                    return Ok(1 + width);
+                };
+                for i in 0..function.upvalue_count {
+                    let operand = offset + 1 + width + 2 * i;
+                    let kind = if self.code[operand] == 1 {
+                        "local"
+                    } else {
+                        "upvalue"
+                    };
+                    let index = self.code[operand + 1];
+                    writeln!(f, "{:04}    |   {kind} {index}", operand + 1)?;
+                }
+                Ok(1 + width + 2 * function.upvalue_count)
             }
-            OpCode::Print | OpCode::OpPop | OpCode::Return => {
+            OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::GetUpvalue
+            | OpCode::SetUpvalue
+            | OpCode::Call => {
+                let slot = self.code[offset + 1];
+                writeln!(f, "{instruction} {slot}")?;
+                Ok(2)
+            }
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+                let jump = u16::from_be_bytes([self.code[offset + 1], self.code[offset + 2]]);
+                let target = self.jump_target(offset).ok_or(std::fmt::Error)?;
+                writeln!(f, "{instruction} {jump} -> {:04}", target + 1)?;
+                Ok(3)
+            }
+            OpCode::Print
+            | OpCode::OpPop
+            | OpCode::CloseUpvalue
+            | OpCode::Inherit
+            | OpCode::Return => {
                 writeln!(f, "{instruction}")?;
-                return Ok(1);
+                Ok(1)
             }
             OpCode::OpAdd
             | OpCode::OpSubtract
             | OpCode::OpMultiply
             | OpCode::OpDivide
-            | OpCode::OpNegate => {
+            | OpCode::OpNegate
+            | OpCode::Not
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less => {
                 writeln!(f, "{instruction}")?;
-                return Ok(1);
+                Ok(1)
             }
         }
     }
 }
 
-// TODO: take chunk as ref.
-pub struct VM {
-    chunk: Chunk,
+struct CallFrame {
+    closure: Gc<Closure>,
     ip: usize,
-    // TODO: Does it need to be public?
-    pub stack: Vec<Value>,
+    // Index of the stack slot holding the called function.
+    slot_base: usize,
+}
+
+pub struct VM {
+    frames: Vec<CallFrame>,
+    pub(crate) stack: Vec<Value>,
+    // Keyed by interned name.
+    globals: HashMap<Gc<String>, Value>,
+    // Upvalues still pointing into the stack, ordered by slot.
+    open_upvalues: Vec<Gc<RefCell<Upvalue>>>,
+    heap: Heap,
+    init_string: Gc<String>,
+    // The top-level function `interpret` runs.
+    script: Gc<Closure>,
+    // The stack as `print` shows it when the last run failed.
+    failed_stack: Vec<String>,
 }
 
 impl VM {
-    pub fn new(chunk: Chunk) -> Self {
-        Self {
-            chunk,
+    /// Takes over the heap the function was compiled into.
+    pub(crate) fn new(function: Function, heap: Heap) -> Self {
+        let init_string = heap.intern("init");
+        let script = heap.alloc(Closure::new(heap.alloc(function)));
+        let mut vm = Self {
+            frames: Vec::with_capacity(FRAMES_MAX),
+            stack: Vec::with_capacity(STACK_MAX),
+            globals: HashMap::new(),
+            open_upvalues: Vec::new(),
+            heap,
+            init_string,
+            script,
+            failed_stack: Vec::new(),
+        };
+        vm.define_native("clock", 0, native::clock);
+        vm.restart();
+        vm
+    }
+
+    /// Compiles `program` into a VM ready to run it.
+    pub fn from_program(program: &mut [ast::Statement]) -> Result<Self, Error> {
+        let heap = Heap::new();
+        let function = compiler::compile(program, &heap)?;
+        Ok(Self::new(function, heap))
+    }
+
+    /// Loads a script written by `bytecode::serialize` into a VM ready to run it.
+    pub fn from_bytecode(bytes: &[u8]) -> Result<Self, Error> {
+        let heap = Heap::new();
+        let function = bytecode::deserialize(bytes, &heap)?;
+        Ok(Self::new(function, heap))
+    }
+
+    /// Compiles `program` as the next script to run, with the globals left by
+    /// the previous one. The REPL runs each input so.
+    pub fn load_program(&mut self, program: &mut [ast::Statement]) -> Result<(), Error> {
+        let function = compiler::compile(program, &self.heap)?;
+        self.load(function);
+        Ok(())
+    }
+
+    /// Sets up another top-level script, compiled into this VM's heap.
+    pub(crate) fn load(&mut self, function: Function) {
+        self.script = self.heap.alloc(Closure::new(self.heap.alloc(function)));
+        self.restart();
+    }
+
+    /// Sets up the script to run from its start.
+    fn restart(&mut self) {
+        self.reset_stack();
+        self.stack.push(Value::Closure(self.script));
+        self.frames.push(CallFrame {
+            closure: self.script,
             ip: 0,
-            stack: Vec::new(),
+            slot_base: 0,
+        });
+    }
+
+    /// Makes a Rust function callable from Lox as the global `name`.
+    pub(crate) fn define_native(&mut self, name: &str, arity: usize, function: NativeFunction) {
+        let native = NativeFn {
+            name: name.into(),
+            arity,
+            function,
+        };
+        let native = self.heap.alloc(native);
+        self.globals
+            .insert(self.heap.intern(name), Value::NativeFn(native));
+    }
+
+    #[cfg(test)]
+    pub(crate) fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub(crate) fn global(&self, name: &str) -> Option<&Value> {
+        let name = self.heap.find_string(name)?;
+        self.globals.get(&name)
+    }
+
+    /// The global `name` as `print` shows it.
+    pub fn global_to_string(&self, name: &str) -> Option<String> {
+        self.global(name).map(Value::to_string)
+    }
+
+    /// The Lox function bound to the global `name`.
+    pub fn global_function(&self, name: &str) -> Option<&Function> {
+        match self.global(name)? {
+            Value::Closure(closure) => Some(&closure.function),
+            Value::Function(function) => Some(function),
+            _ => None,
         }
     }
 
+    /// The top-level function of the loaded script.
+    pub fn script(&self) -> &Function {
+        &self.script.function
+    }
+
+    /// The value stack at the instruction where the last run failed, as
+    /// `print` shows each value. Empty if it did not fail.
+    pub fn failed_stack(&self) -> &[String] {
+        &self.failed_stack
+    }
+
+    /// Frees every object unreachable from the stack, the active frames, the
+    /// globals and the open upvalues. Only called between instructions, where
+    /// every value the VM still uses is reachable from those roots.
+    fn collect_garbage(&mut self) {
+        // SAFETY: see above. Handles never leave the crate, and nothing in
+        // it keeps one across a call to `interpret`.
+        unsafe {
+            self.heap.collect(|tracer| {
+                for val in &self.stack {
+                    val.trace(tracer);
+                }
+                for frame in &self.frames {
+                    tracer.mark(frame.closure);
+                }
+                for (name, val) in &self.globals {
+                    tracer.mark(*name);
+                    val.trace(tracer);
+                }
+                for upvalue in &self.open_upvalues {
+                    tracer.mark(*upvalue);
+                }
+                tracer.mark(self.init_string);
+                tracer.mark(self.script);
+            })
+        };
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("no active call frame")
+    }
+
+    fn chunk(&self) -> &Chunk {
+        &self.frame().closure.function.chunk
+    }
+
     fn read_byte(&mut self) -> u8 {
-        let byte = self.chunk.code[self.ip];
-        self.ip += 1;
+        let frame = self.frames.last_mut().expect("no active call frame");
+        let byte = frame.closure.function.chunk.code[frame.ip];
+        frame.ip += 1;
         byte
     }
 
+    /// The operands of a binary operator. They stay on the stack until
+    /// `push_binary_result`, so a failing operator leaves them there.
+    fn binary_operands(&self) -> Result<(Value, Value), Error> {
+        match self.stack.len().checked_sub(2) {
+            Some(base) => Ok((self.stack[base].clone(), self.stack[base + 1].clone())),
+            None => Err(Error::stack_underflow(
+                "Corruption while doing binary operator",
+            )),
+        }
+    }
+
+    /// Replaces the operands of a binary operator with its result.
+    fn push_binary_result(&mut self, result: Value) {
+        self.stack.truncate(self.stack.len() - 2);
+        self.stack.push(result);
+    }
+
+    fn read_short(&mut self) -> u16 {
+        let hi = self.read_byte();
+        let lo = self.read_byte();
+        u16::from_be_bytes([hi, lo])
+    }
+
+    /// Reads the constant index operand of `instruction`.
+    fn read_constant_id(&mut self, instruction: OpCode) -> usize {
+        if instruction.is_long() {
+            let [a, b, c] = [self.read_byte(), self.read_byte(), self.read_byte()];
+            u32::from_be_bytes([0, a, b, c]) as usize
+        } else {
+            self.read_byte() as usize
+        }
+    }
+
+    fn read_name(&mut self, instruction: OpCode) -> Gc<String> {
+        let const_id = self.read_constant_id(instruction);
+        match &self.chunk().constants[const_id] {
+            Value::String(name) => *name,
+            // The compiler only emits named instructions with string constants.
+            other => unreachable!("name is not a string: {other}"),
+        }
+    }
+
+    fn call_value(&mut self, arg_count: usize) -> Result<(), Error> {
+        let callee = &self.stack[self.stack.len() - 1 - arg_count];
+        match callee {
+            Value::Closure(closure) => self.call(*closure, arg_count),
+            Value::NativeFn(native) => {
+                let native = *native;
+                if arg_count != native.arity {
+                    return Err(Error::arity_mismatch(native.arity, arg_count));
+                }
+                // The arguments stay on the stack so they remain rooted while
+                // the native runs.
+                let args = self.stack[self.stack.len() - arg_count..].to_vec();
+                let result = (native.function)(self, &args)?;
+                self.stack.truncate(self.stack.len() - arg_count - 1);
+                self.stack.push(result);
+                Ok(())
+            }
+            Value::BoundMethod(bound) => {
+                let method = bound.method;
+                let slot = self.stack.len() - 1 - arg_count;
+                self.stack[slot] = bound.receiver.clone();
+                self.call(method, arg_count)
+            }
+            Value::Class(class) => {
+                let class = *class;
+                let slot = self.stack.len() - 1 - arg_count;
+                let instance = self.heap.alloc(RefCell::new(Instance::new(class)));
+                self.stack[slot] = Value::Instance(instance);
+                let initializer = class.borrow().methods.get(&self.init_string).copied();
+                match initializer {
+                    Some(initializer) => self.call(initializer, arg_count),
+                    None if arg_count != 0 => Err(Error::arity_mismatch(0, arg_count)),
+                    None => Ok(()),
+                }
+            }
+            other => Err(Error::type_error("call", other.type_name())),
+        }
+    }
+
+    /// Replaces the instance on top of the stack with its method `name`.
+    fn bind_method(&mut self, class: Gc<RefCell<Class>>, name: Gc<String>) -> Result<(), Error> {
+        let method = class
+            .borrow()
+            .methods
+            .get(&name)
+            .copied()
+            .ok_or_else(|| Error::undefined_property(name.as_str()))?;
+        let receiver = self
+            .stack
+            .pop()
+            .ok_or_else(|| Error::stack_underflow("No receiver to bind"))?;
+        let bound = BoundMethod { receiver, method };
+        self.stack.push(Value::BoundMethod(self.heap.alloc(bound)));
+        Ok(())
+    }
+
+    fn call(&mut self, closure: Gc<Closure>, arg_count: usize) -> Result<(), Error> {
+        let arity = closure.function.arity;
+        if arg_count != arity {
+            return Err(Error::arity_mismatch(arity, arg_count));
+        }
+        if self.frames.len() == FRAMES_MAX {
+            return Err(Error::stack_overflow());
+        }
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slot_base: self.stack.len() - 1 - arg_count,
+        });
+        Ok(())
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> Gc<RefCell<Upvalue>> {
+        let position = self
+            .open_upvalues
+            .partition_point(|upvalue| matches!(*upvalue.borrow(), Upvalue::Open(s) if s < slot));
+        if let Some(upvalue) = self.open_upvalues.get(position)
+            && matches!(*upvalue.borrow(), Upvalue::Open(s) if s == slot)
+        {
+            return *upvalue;
+        }
+        let upvalue = self.heap.alloc(RefCell::new(Upvalue::Open(slot)));
+        self.open_upvalues.insert(position, upvalue);
+        upvalue
+    }
+
+    /// Moves every captured variable at or above `last` off the stack.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(upvalue) = self.open_upvalues.last() {
+            let mut upvalue = upvalue.borrow_mut();
+            let Upvalue::Open(slot) = *upvalue else {
+                unreachable!("closed upvalue in the open list");
+            };
+            if slot < last {
+                break;
+            }
+            *upvalue = Upvalue::Closed(self.stack[slot].clone());
+            drop(upvalue);
+            self.open_upvalues.pop();
+        }
+    }
+
+    /// Runs until the outermost frame returns, leaving its result on the stack.
+    /// Once the script has returned or failed, it runs again from its start.
     pub fn interpret(&mut self) -> Result<(), Error> {
+        if self.frames.is_empty() {
+            self.restart();
+        }
+        self.failed_stack.clear();
+        self.run().map_err(|error| {
+            let error = self.runtime_error(error);
+            self.failed_stack = self.stack.iter().map(Value::to_string).collect();
+            self.reset_stack();
+            error
+        })
+    }
+
+    /// Locates `error` at the faulting instruction and records the active calls.
+    fn runtime_error(&self, error: Error) -> Error {
+        // Operands share the position of their instruction, so the last byte
+        // read locates the instruction. In callers that is the pending `Call`.
+        let position = |frame: &CallFrame| {
+            let chunk = &frame.closure.function.chunk;
+            chunk.position(frame.ip.saturating_sub(1)).unwrap_or((0, 0))
+        };
+        let trace = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| TraceFrame {
+                function: frame.closure.function.name.clone(),
+                line: position(frame).0,
+            })
+            .collect();
+        let (line, column) = position(self.frame());
+        Error::runtime(line, column, error, trace)
+    }
+
+    fn reset_stack(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
+    }
+
+    fn run(&mut self) -> Result<(), Error> {
         loop {
+            // Between instructions every live value is reachable from the roots.
+            if self.heap.should_collect() {
+                self.collect_garbage();
+            }
             let next_byte = self.read_byte();
             let instruction = OpCode::try_from(next_byte)
-                .map_err(|_| Error::invalid_instruction(next_byte, self.ip - 1))?;
+                .map_err(|_| Error::invalid_instruction(next_byte, self.frame().ip - 1))?;
             match instruction {
-                OpCode::Return => return Ok(()),
-                OpCode::Constant => {
-                    let const_id = self.read_byte() as usize;
-                    self.stack.push(self.chunk.constants[const_id].clone());
+                OpCode::Return => {
+                    let result = self
+                        .stack
+                        .pop()
+                        .ok_or_else(|| Error::stack_underflow("No value to return"))?;
+                    let frame = self.frames.pop().expect("no active call frame");
+                    self.close_upvalues(frame.slot_base);
+                    self.stack.truncate(frame.slot_base);
+                    self.stack.push(result);
+                    if self.frames.is_empty() {
+                        return Ok(());
+                    }
+                }
+                OpCode::Constant | OpCode::ConstantLong => {
+                    let const_id = self.read_constant_id(instruction);
+                    self.stack.push(self.chunk().constants[const_id].clone());
                 }
                 OpCode::OpAdd => {
-                    if let (Some(b), Some(a)) = (self.stack.pop(), self.stack.pop()) {
-                        let result = Value::checked_add(a, b)?;
-                        self.stack.push(result);
-                    } else {
-                        return Err(Error::stack_underflow(
-                            "Corruption while doing binary operator",
-                        ));
-                    }
+                    let (a, b) = self.binary_operands()?;
+                    let result = Value::checked_add(a, b, &self.heap)?;
+                    self.push_binary_result(result);
                 }
                 OpCode::OpSubtract => {
-                    if let (Some(b), Some(a)) = (self.stack.pop(), self.stack.pop()) {
-                        let result = Value::checked_sub(a, b)?;
-                        self.stack.push(result);
+                    let (a, b) = self.binary_operands()?;
+                    let result = Value::checked_sub(a, b)?;
+                    self.push_binary_result(result);
+                }
+                OpCode::OpMultiply => {
+                    let (a, b) = self.binary_operands()?;
+                    let result = Value::checked_mul(a, b)?;
+                    self.push_binary_result(result);
+                }
+                OpCode::OpDivide => {
+                    let (a, b) = self.binary_operands()?;
+                    let result = Value::checked_div(a, b)?;
+                    self.push_binary_result(result);
+                }
+                OpCode::OpNegate => {
+                    // TODO: Examine this code.
+                    if let Some(a) = self.stack.last_mut() {
+                        *a = Value::checked_neg(a.clone())?;
                     } else {
                         return Err(Error::stack_underflow(
-                            "Corruption while doing binary operator",
+                            "Corruption while doing unary operator",
                         ));
                     }
                 }
-                OpCode::OpMultiply => {
-                    if let (Some(b), Some(a)) = (self.stack.pop(), self.stack.pop()) {
-                        let result = Value::checked_mul(a, b)?;
-                        self.stack.push(result);
+                OpCode::Not => {
+                    if let Some(a) = self.stack.last_mut() {
+                        *a = Value::from(a.is_falsey());
                     } else {
                         return Err(Error::stack_underflow(
-                            "Corruption while doing binary operator",
+                            "Corruption while doing unary operator",
                         ));
                     }
                 }
-                OpCode::OpDivide => {
+                OpCode::Equal => {
                     if let (Some(b), Some(a)) = (self.stack.pop(), self.stack.pop()) {
-                        let result = Value::checked_div(a, b)?;
-                        self.stack.push(result);
+                        self.stack.push(Value::from(a == b));
                     } else {
                         return Err(Error::stack_underflow(
                             "Corruption while doing binary operator",
                         ));
                     }
                 }
-                OpCode::OpNegate => {
-                    // TODO: Examine this code.
-                    if let Some(a) = self.stack.last_mut() {
-                        *a = Value::checked_neg(a.clone())?;
-                    } else {
-                        return Err(Error::stack_underflow(
-                            "Corruption while doing unary operator",
-                        ));
-                    }
+                OpCode::Greater => {
+                    let (a, b) = self.binary_operands()?;
+                    let result = Value::checked_greater(a, b)?;
+                    self.push_binary_result(result);
+                }
+                OpCode::Less => {
+                    let (a, b) = self.binary_operands()?;
+                    let result = Value::checked_less(a, b)?;
+                    self.push_binary_result(result);
                 }
                 OpCode::OpPop => {
                     self.stack
                         .pop()
                         .ok_or_else(|| Error::stack_underflow("No value to pop"))?;
                 }
+                OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                    let name = self.read_name(instruction);
+                    let val = self
+                        .stack
+                        .pop()
+                        .ok_or_else(|| Error::stack_underflow("No value to define"))?;
+                    self.globals.insert(name, val);
+                }
+                OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                    let name = self.read_name(instruction);
+                    let val = self
+                        .globals
+                        .get(&name)
+                        .ok_or_else(|| Error::undefined_variable(name.as_str()))?;
+                    self.stack.push(val.clone());
+                }
+                OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                    let name = self.read_name(instruction);
+                    let val = self
+                        .stack
+                        .last()
+                        .ok_or_else(|| Error::stack_underflow("No value to assign"))?;
+                    let Some(global) = self.globals.get_mut(&name) else {
+                        return Err(Error::undefined_variable(name.as_str()));
+                    };
+                    *global = val.clone();
+                }
+                OpCode::GetLocal => {
+                    let slot = self.frame().slot_base + self.read_byte() as usize;
+                    let val = self
+                        .stack
+                        .get(slot)
+                        .ok_or_else(|| Error::stack_underflow("Reading local variable"))?;
+                    self.stack.push(val.clone());
+                }
+                OpCode::SetLocal => {
+                    let slot = self.frame().slot_base + self.read_byte() as usize;
+                    let val = self
+                        .stack
+                        .last()
+                        .ok_or_else(|| Error::stack_underflow("No value to assign"))?
+                        .clone();
+                    let local = self
+                        .stack
+                        .get_mut(slot)
+                        .ok_or_else(|| Error::stack_underflow("Writing local variable"))?;
+                    *local = val;
+                }
+                OpCode::Jump => {
+                    let offset = self.read_short() as usize;
+                    self.frames.last_mut().expect("no active call frame").ip += offset;
+                }
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_short() as usize;
+                    let condition = self
+                        .stack
+                        .last()
+                        .ok_or_else(|| Error::stack_underflow("No condition to test"))?;
+                    if condition.is_falsey() {
+                        self.frames.last_mut().expect("no active call frame").ip += offset;
+                    }
+                }
+                OpCode::Loop => {
+                    let offset = self.read_short() as usize;
+                    self.frames.last_mut().expect("no active call frame").ip -= offset;
+                }
+                OpCode::Call => {
+                    let arg_count = self.read_byte() as usize;
+                    self.call_value(arg_count)?;
+                }
+                OpCode::Closure | OpCode::ClosureLong => {
+                    let const_id = self.read_constant_id(instruction);
+                    let Value::Function(function) = &self.chunk().constants[const_id] else {
+                        unreachable!("closure constant is not a function");
+                    };
+                    let mut closure = Closure::new(*function);
+                    for _ in 0..closure.function.upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+                        let upvalue = if is_local {
+                            self.capture_upvalue(self.frame().slot_base + index)
+                        } else {
+                            self.frame().closure.upvalues[index]
+                        };
+                        closure.upvalues.push(upvalue);
+                    }
+                    self.stack.push(Value::Closure(self.heap.alloc(closure)));
+                }
+                OpCode::GetUpvalue => {
+                    let index = self.read_byte() as usize;
+                    let val = match &*self.frame().closure.upvalues[index].borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        Upvalue::Closed(val) => val.clone(),
+                    };
+                    self.stack.push(val);
+                }
+                OpCode::SetUpvalue => {
+                    let index = self.read_byte() as usize;
+                    let val = self
+                        .stack
+                        .last()
+                        .ok_or_else(|| Error::stack_underflow("No value to assign"))?
+                        .clone();
+                    let upvalue = self.frame().closure.upvalues[index];
+                    match &mut *upvalue.borrow_mut() {
+                        Upvalue::Open(slot) => self.stack[*slot] = val,
+                        Upvalue::Closed(closed) => *closed = val,
+                    }
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.stack.pop();
+                }
+                OpCode::Class | OpCode::ClassLong => {
+                    let name = self.read_name(instruction);
+                    let class = Class::new(&name);
+                    self.stack
+                        .push(Value::Class(self.heap.alloc(RefCell::new(class))));
+                }
+                OpCode::GetProperty | OpCode::GetPropertyLong => {
+                    let name = self.read_name(instruction);
+                    let instance = match self.stack.last() {
+                        Some(Value::Instance(instance)) => *instance,
+                        Some(other) => {
+                            return Err(Error::type_error("read property", other.type_name()));
+                        }
+                        None => return Err(Error::stack_underflow("No object to read")),
+                    };
+                    let field = instance.borrow().fields.get(&name).cloned();
+                    if let Some(val) = field {
+                        self.stack.pop();
+                        self.stack.push(val);
+                    } else {
+                        let class = instance.borrow().class;
+                        self.bind_method(class, name)?;
+                    }
+                }
+                OpCode::SetProperty | OpCode::SetPropertyLong => {
+                    let name = self.read_name(instruction);
+                    let (Some(val), Some(object)) = (self.stack.pop(), self.stack.pop()) else {
+                        return Err(Error::stack_underflow("No property to set"));
+                    };
+                    let Value::Instance(instance) = object else {
+                        return Err(Error::type_error("set property", object.type_name()));
+                    };
+                    instance.borrow_mut().fields.insert(name, val.clone());
+                    self.stack.push(val);
+                }
+                OpCode::Method | OpCode::MethodLong => {
+                    let name = self.read_name(instruction);
+                    let Some(Value::Closure(method)) = self.stack.pop() else {
+                        return Err(Error::stack_underflow("No method to define"));
+                    };
+                    let Some(Value::Class(class)) = self.stack.last() else {
+                        return Err(Error::stack_underflow("No class to define a method on"));
+                    };
+                    class.borrow_mut().methods.insert(name, method);
+                }
+                OpCode::Inherit => {
+                    let Some(Value::Class(subclass)) = self.stack.pop() else {
+                        return Err(Error::stack_underflow("No class to inherit into"));
+                    };
+                    let superclass = match self.stack.last() {
+                        Some(Value::Class(superclass)) => *superclass,
+                        Some(other) => {
+                            return Err(Error::type_error("inherit from", other.type_name()));
+                        }
+                        None => return Err(Error::stack_underflow("No superclass to inherit")),
+                    };
+                    // Methods are copied down now, so the subclass's own
+                    // methods defined afterwards override them.
+                    let methods = superclass.borrow().methods.clone();
+                    subclass.borrow_mut().methods.extend(methods);
+                }
+                OpCode::GetSuper | OpCode::GetSuperLong => {
+                    let name = self.read_name(instruction);
+                    let Some(Value::Class(superclass)) = self.stack.pop() else {
+                        return Err(Error::stack_underflow("No superclass to look up"));
+                    };
+                    self.bind_method(superclass, name)?;
+                }
                 OpCode::Print => {
                     let val = self
                         .stack
@@ -210,21 +1019,28 @@ impl VM {
 
 #[test]
 fn tests() {
+    fn runtime_error(result: Result<(), Error>) -> Error {
+        match result {
+            Err(Error::Runtime { error, .. }) => *error,
+            other => panic!("expected a runtime error, got {:?}", other),
+        }
+    }
+
     {
         let mut chunk = Chunk::new("test bytes 1");
 
         let id = chunk.write_constant(1.25);
-        chunk.emit(OpCode::Constant);
-        chunk.emit(id as u8);
-        chunk.emit(OpCode::Return);
+        chunk.emit(OpCode::Constant, 1, 1);
+        chunk.emit(id as u8, 1, 1);
+        chunk.emit(OpCode::Return, 1, 1);
 
         let output = format!("{:?}", chunk);
         let mut dissassembled = output.lines();
         assert_eq!(dissassembled.next(), Some("=== test bytes 1 ==="));
-        assert_eq!(dissassembled.next(), Some("0001 - Const 0 (1.25)"));
-        assert_eq!(dissassembled.next(), Some("0003 - Return"));
+        assert_eq!(dissassembled.next(), Some("0001    1 Const 0 (1.25)"));
+        assert_eq!(dissassembled.next(), Some("0003    | Return"));
 
-        let mut vm = VM::new(chunk);
+        let mut vm = VM::new(Function::script(chunk), Heap::new());
         vm.interpret().unwrap();
         assert_eq!(vm.stack.len(), 1);
         assert_eq!(vm.stack.first(), Some(&Value::from(1.25)));
@@ -234,33 +1050,684 @@ fn tests() {
 
         // - ((1.25 + 3.5) / 4.75)
         let id = chunk.write_constant(1.25);
-        chunk.emit(OpCode::Constant);
-        chunk.emit(id as u8);
+        chunk.emit(OpCode::Constant, 1, 5);
+        chunk.emit(id as u8, 1, 5);
         let id = chunk.write_constant(3.5);
-        chunk.emit(OpCode::Constant);
-        chunk.emit(id as u8);
-        chunk.emit(OpCode::OpAdd);
+        chunk.emit(OpCode::Constant, 1, 12);
+        chunk.emit(id as u8, 1, 12);
+        chunk.emit(OpCode::OpAdd, 1, 10);
         let id = chunk.write_constant(4.75);
-        chunk.emit(OpCode::Constant);
-        chunk.emit(id as u8);
-        chunk.emit(OpCode::OpDivide);
-        chunk.emit(OpCode::OpNegate);
-        chunk.emit(OpCode::Return);
+        chunk.emit(OpCode::Constant, 2, 3);
+        chunk.emit(id as u8, 2, 3);
+        chunk.emit(OpCode::OpDivide, 2, 1);
+        chunk.emit(OpCode::OpNegate, 2, 1);
+        chunk.emit(OpCode::Return, 3, 1);
+        assert_eq!(chunk.lines.len(), 6);
+        assert_eq!(chunk.position(3), Some((1, 12)));
+        assert_eq!(chunk.position(8), Some((2, 1)));
+        assert_eq!(chunk.position(10), None);
 
         let output = format!("{:?}", chunk);
         let mut dissassembled = output.lines();
         assert_eq!(dissassembled.next(), Some("=== test bytes 2 ==="));
-        assert_eq!(dissassembled.next(), Some("0001 - Const 0 (1.25)"));
-        assert_eq!(dissassembled.next(), Some("0003 - Const 1 (3.5)"));
-        assert_eq!(dissassembled.next(), Some("0005 - Add"));
-        assert_eq!(dissassembled.next(), Some("0006 - Const 2 (4.75)"));
-        assert_eq!(dissassembled.next(), Some("0008 - Div"));
-        assert_eq!(dissassembled.next(), Some("0009 - Neg"));
-        assert_eq!(dissassembled.next(), Some("0010 - Return"));
-
-        let mut vm = VM::new(chunk);
+        assert_eq!(dissassembled.next(), Some("0001    1 Const 0 (1.25)"));
+        assert_eq!(dissassembled.next(), Some("0003    | Const 1 (3.5)"));
+        assert_eq!(dissassembled.next(), Some("0005    | Add"));
+        assert_eq!(dissassembled.next(), Some("0006    2 Const 2 (4.75)"));
+        assert_eq!(dissassembled.next(), Some("0008    | Div"));
+        assert_eq!(dissassembled.next(), Some("0009    | Neg"));
+        assert_eq!(dissassembled.next(), Some("0010    3 Return"));
+
+        let mut vm = VM::new(Function::script(chunk), Heap::new());
         vm.interpret().unwrap();
         assert_eq!(vm.stack.len(), 1);
         assert_eq!(vm.stack.first(), Some(&Value::from(-1.0)));
     }
+    {
+        let input = "var a = 1; var b; b = a = a + 2; var c = a * b;";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        vm.interpret().unwrap();
+        assert_eq!(vm.stack, [Value::nil()]);
+        assert_eq!(vm.global("a"), Some(&Value::from(3.0)));
+        assert_eq!(vm.global("b"), Some(&Value::from(3.0)));
+        assert_eq!(vm.global("c"), Some(&Value::from(9.0)));
+    }
+    {
+        let mut program = crate::Parser::new("var zero = 0;\nprint 0 / zero;")
+            .statements()
+            .unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        assert!(matches!(
+            vm.interpret(),
+            Err(Error::Runtime { line: 2, column: 9, error, .. })
+                if matches!(*error, Error::DivisionByZero)
+        ));
+    }
+    {
+        // Unary plus leaves numbers as they are and rejects anything else.
+        let mut program = crate::Parser::new("var a = -0; var b = +a; var c = +\"c\";")
+            .statements()
+            .unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        assert!(matches!(
+            runtime_error(vm.interpret()),
+            Error::TypeError { .. }
+        ));
+        let Some(Value::Number(b)) = vm.global("b") else {
+            panic!("expected a number");
+        };
+        assert!(b.is_sign_negative() && *b == 0.0);
+    }
+    {
+        let mut program = crate::Parser::new("print missing;").statements().unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        assert!(matches!(
+            runtime_error(vm.interpret()),
+            Error::UndefinedVariable { name } if name == "missing"
+        ));
+
+        let mut program = crate::Parser::new("missing = 1;").statements().unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        assert!(matches!(
+            runtime_error(vm.interpret()),
+            Error::UndefinedVariable { name } if name == "missing"
+        ));
+        assert!(vm.global("missing").is_none());
+    }
+    {
+        let input =
+            "fun check(n) {\n  return n + nil;\n}\nfun outer() {\n  return check(1);\n}\nouter();";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        let error = vm.interpret().unwrap_err();
+        let Error::Runtime { trace, .. } = &error else {
+            panic!("expected a runtime error, got {:?}", error);
+        };
+        let frame = |function: Option<&str>, line| TraceFrame {
+            function: function.map(String::from),
+            line,
+        };
+        assert_eq!(
+            *trace,
+            [
+                frame(Some("check"), 2),
+                frame(Some("outer"), 5),
+                frame(None, 7)
+            ]
+        );
+        assert_eq!(
+            error.to_string().lines().skip(1).collect::<Vec<_>>(),
+            [
+                "[line 2] in check()",
+                "[line 5] in outer()",
+                "[line 7] in script"
+            ]
+        );
+        // The VM is left with a clean stack.
+        assert!(vm.stack.is_empty());
+        assert!(vm.frames.is_empty());
+    }
+    {
+        // Runtime errors point at the operator that failed.
+        let mut program = crate::Parser::new("var a = 1;\nvar b = a + \"x\";")
+            .statements()
+            .unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        assert!(matches!(
+            vm.interpret(),
+            Err(Error::Runtime { line: 2, column: 11, error, .. }) if matches!(*error, Error::TypeError { .. })
+        ));
+    }
+    {
+        let input =
+            "var a = 1; var b; { var a = 10; { var c = a + 1; b = c; } a = a * 2; b = b + a; }";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        vm.interpret().unwrap();
+        assert_eq!(vm.stack, [Value::nil()]);
+        assert_eq!(vm.global("a"), Some(&Value::from(1.0)));
+        assert_eq!(vm.global("b"), Some(&Value::from(31.0)));
+    }
+    {
+        let input = "
+            var none;
+            var a = 0;
+            var b = 0;
+            if (none) a = 1; else a = 2;
+            if (a) { b = 3; }
+            // Numbers are always truthy, so loops are driven by values turning nil.
+            var count = 0;
+            var x = 1;
+            var y = 1;
+            var z = 1;
+            while (x) {
+                count = count + 1;
+                x = y;
+                y = z;
+                z = none;
+            }
+            var sum = 0;
+            var next;
+            var later = 5;
+            for (var i = 10; i; i = next) {
+                sum = sum + i;
+                next = later;
+                later = none;
+            }
+        ";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        vm.interpret().unwrap();
+        assert_eq!(vm.stack, [Value::nil()]);
+        assert_eq!(vm.global("a"), Some(&Value::from(2.0)));
+        assert_eq!(vm.global("b"), Some(&Value::from(3.0)));
+        assert_eq!(vm.global("count"), Some(&Value::from(3.0)));
+        assert_eq!(vm.global("sum"), Some(&Value::from(15.0)));
+        assert!(vm.global("i").is_none());
+    }
+    {
+        let input = "
+            var greeting = \"hello\" + \" \" + \"world\";
+            var same = greeting == \"hello world\";
+            var different = 1 != \"1\";
+            var nothing = nil == false;
+            var truthy = !nil == !false;
+            var count = 0;
+            while (count < 3) count = count + 1;
+        ";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        vm.interpret().unwrap();
+        assert_eq!(
+            vm.global("greeting"),
+            Some(&Value::string(vm.heap(), "hello world"))
+        );
+        assert_eq!(vm.global("same"), Some(&Value::from(true)));
+        assert_eq!(vm.global("different"), Some(&Value::from(true)));
+        assert_eq!(vm.global("nothing"), Some(&Value::from(false)));
+        assert_eq!(vm.global("truthy"), Some(&Value::from(true)));
+        assert_eq!(vm.global("count"), Some(&Value::from(3.0)));
+    }
+    {
+        let mut program = crate::Parser::new("1 < \"2\";").statements().unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        assert!(matches!(
+            runtime_error(vm.interpret()),
+            Error::TypeError { .. }
+        ));
+    }
+    {
+        let input = "
+            var calls = 0;
+            var a = false and (calls = calls + 1);
+            var b = nil and (calls = calls + 1);
+            var c = 1 or (calls = calls + 1);
+            var d = true or (calls = calls + 1);
+            var e = 1 and (calls = calls + 10);
+            var f = false or (calls = calls + 100);
+            var g = nil or false;
+            var h = 1 and 2 or 3;
+        ";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        vm.interpret().unwrap();
+        assert_eq!(vm.stack, [Value::nil()]);
+        assert_eq!(vm.global("calls"), Some(&Value::from(110.0)));
+        assert_eq!(vm.global("a"), Some(&Value::from(false)));
+        assert_eq!(vm.global("b"), Some(&Value::nil()));
+        assert_eq!(vm.global("c"), Some(&Value::from(1.0)));
+        assert_eq!(vm.global("d"), Some(&Value::from(true)));
+        assert_eq!(vm.global("e"), Some(&Value::from(10.0)));
+        assert_eq!(vm.global("f"), Some(&Value::from(110.0)));
+        assert_eq!(vm.global("g"), Some(&Value::from(false)));
+        assert_eq!(vm.global("h"), Some(&Value::from(2.0)));
+    }
+    {
+        let input = "
+            fun fib(n) {
+                if (n < 2) return n;
+                return fib(n - 1) + fib(n - 2);
+            }
+            fun greet(name) {
+                var greeting = \"hello \" + name;
+                return greeting;
+            }
+            fun nothing() {}
+            var result = fib(10);
+            var message = greet(\"lox\");
+            var empty = nothing();
+            {
+                fun local(x) { return x * 2; }
+                result = result + local(1);
+            }
+        ";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        vm.interpret().unwrap();
+        assert_eq!(vm.stack, [Value::nil()]);
+        assert_eq!(vm.global("result"), Some(&Value::from(57.0)));
+        assert_eq!(
+            vm.global("message"),
+            Some(&Value::string(vm.heap(), "hello lox"))
+        );
+        assert_eq!(vm.global("empty"), Some(&Value::nil()));
+    }
+    {
+        let mut program = crate::Parser::new("fun f(a) {} f(1, 2);")
+            .statements()
+            .unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        assert!(matches!(
+            runtime_error(vm.interpret()),
+            Error::ArityMismatch {
+                expected: 1,
+                found: 2
+            }
+        ));
+
+        // Deep recursion is fine up to the frame limit.
+        let mut program = crate::Parser::new(
+            "fun depth(n) { if (n == 0) return 0; return 1 + depth(n - 1); } var d = depth(250);",
+        )
+        .statements()
+        .unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        vm.interpret().unwrap();
+        assert_eq!(vm.global("d"), Some(&Value::from(250.0)));
+
+        let mut program = crate::Parser::new("fun f() { f(); } f();")
+            .statements()
+            .unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        assert!(matches!(
+            runtime_error(vm.interpret()),
+            Error::StackOverflow
+        ));
+
+        let mut program = crate::Parser::new("\"not a function\"();")
+            .statements()
+            .unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        assert!(matches!(
+            runtime_error(vm.interpret()),
+            Error::TypeError { .. }
+        ));
+    }
+    {
+        let input = "
+            fun makeCounter() {
+                var count = 0;
+                fun increment() {
+                    count = count + 1;
+                    return count;
+                }
+                return increment;
+            }
+            var first = makeCounter();
+            var second = makeCounter();
+            first();
+            first();
+            second();
+            var a = first();
+            var b = second();
+
+            var getter;
+            var setter;
+            {
+                var shared = \"before\";
+                fun get() { return shared; }
+                fun set(value) { shared = value; }
+                getter = get;
+                setter = set;
+            }
+            setter(\"after\");
+            var c = getter();
+
+            var sum = 0;
+            for (var i = 1; i <= 3; i = i + 1) {
+                var captured = i;
+                fun add() { sum = sum + captured; }
+                add();
+            }
+
+            fun outer() {
+                var x = \"outer\";
+                fun middle() {
+                    fun inner() { return x; }
+                    return inner;
+                }
+                return middle;
+            }
+            var d = outer()()();
+        ";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        vm.interpret().unwrap();
+        assert_eq!(vm.stack, [Value::nil()]);
+        assert!(vm.open_upvalues.is_empty());
+        assert_eq!(vm.global("a"), Some(&Value::from(3.0)));
+        assert_eq!(vm.global("b"), Some(&Value::from(2.0)));
+        assert_eq!(vm.global("c"), Some(&Value::string(vm.heap(), "after")));
+        assert_eq!(vm.global("sum"), Some(&Value::from(6.0)));
+        assert_eq!(vm.global("d"), Some(&Value::string(vm.heap(), "outer")));
+    }
+    {
+        let input = "
+            class Counter {
+                init(start) {
+                    this.count = start;
+                }
+                increment() {
+                    this.count = this.count + 1;
+                    return this;
+                }
+                adder() {
+                    fun add(n) { this.count = this.count + n; }
+                    return add;
+                }
+            }
+            var counter = Counter(10);
+            counter.increment().increment();
+            var add = counter.adder();
+            add(5);
+            var count = counter.count;
+            var increment = counter.increment;
+            increment();
+            var after = counter.count;
+            var reinit = counter.init(1) == counter;
+            class Empty {}
+            var empty = Empty();
+            empty.field = \"set later\";
+            var field = empty.field;
+        ";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        vm.interpret().unwrap();
+        assert_eq!(vm.stack, [Value::nil()]);
+        assert_eq!(vm.global("count"), Some(&Value::from(17.0)));
+        assert_eq!(vm.global("after"), Some(&Value::from(18.0)));
+        assert_eq!(vm.global("reinit"), Some(&Value::from(true)));
+        assert_eq!(
+            vm.global("field"),
+            Some(&Value::string(vm.heap(), "set later"))
+        );
+        assert_eq!(
+            vm.global("counter").map(|c| c.to_string()),
+            Some("Counter instance".into())
+        );
+        assert_eq!(
+            vm.global("increment").map(|c| c.to_string()),
+            Some("<fn increment>".into())
+        );
+    }
+    {
+        let input = "
+            class Tree {
+                init(item, depth) {
+                    this.item = item;
+                    this.depth = depth;
+                    if (depth > 0) {
+                        var item2 = item + item;
+                        depth = depth - 1;
+                        this.left = Tree(item2 - 1, depth);
+                        this.right = Tree(item2, depth);
+                    } else {
+                        this.left = nil;
+                        this.right = nil;
+                    }
+                }
+                check() {
+                    if (this.left == nil) {
+                        return this.item;
+                    }
+                    return this.item + this.left.check() - this.right.check();
+                }
+            }
+            var check = Tree(3, 4).check();
+        ";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        vm.interpret().unwrap();
+        assert_eq!(vm.global("check"), Some(&Value::from(2.0)));
+    }
+    {
+        let mut program = crate::Parser::new("class A {} A().missing;")
+            .statements()
+            .unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        assert!(matches!(
+            runtime_error(vm.interpret()),
+            Error::UndefinedProperty { name } if name == "missing"
+        ));
+
+        let mut program = crate::Parser::new("class A {} A(1);").statements().unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        assert!(matches!(
+            runtime_error(vm.interpret()),
+            Error::ArityMismatch { .. }
+        ));
+
+        let mut program = crate::Parser::new("var a = 1; a.b = 2;")
+            .statements()
+            .unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        assert!(matches!(
+            runtime_error(vm.interpret()),
+            Error::TypeError { .. }
+        ));
+    }
+    {
+        let input = "
+            class Animal {
+                init(name) { this.name = name; }
+                speak() { return this.name + \" makes a sound\"; }
+                kind() { return \"animal\"; }
+            }
+            class Dog < Animal {
+                init(name) {
+                    super.init(name);
+                    this.tricks = 0;
+                }
+                speak() { return super.speak() + \" (woof)\"; }
+            }
+            class Puppy < Dog {
+                speak() {
+                    var parent = super.speak;
+                    return parent() + \"!\";
+                }
+            }
+            var dog = Dog(\"rex\");
+            var speech = dog.speak();
+            var kind = dog.kind();
+            var puppy = Puppy(\"bit\").speak();
+        ";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        vm.interpret().unwrap();
+        assert_eq!(vm.stack, [Value::nil()]);
+        assert_eq!(
+            vm.global("speech"),
+            Some(&Value::string(vm.heap(), "rex makes a sound (woof)"))
+        );
+        assert_eq!(vm.global("kind"), Some(&Value::string(vm.heap(), "animal")));
+        assert_eq!(
+            vm.global("puppy"),
+            Some(&Value::string(vm.heap(), "bit makes a sound (woof)!"))
+        );
+    }
+    {
+        let mut program = crate::Parser::new("var A = 1; class B < A {}")
+            .statements()
+            .unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        assert!(matches!(
+            runtime_error(vm.interpret()),
+            Error::TypeError { .. }
+        ));
+    }
+    {
+        let input = "var start = clock(); var later = clock(); var elapsed = later - start;";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        vm.interpret().unwrap();
+        assert!(matches!(vm.global("elapsed"), Some(Value::Number(x)) if *x >= 0.0));
+    }
+    {
+        fn fail(_: &mut VM, args: &[Value]) -> Result<Value, Error> {
+            Err(Error::native_error("fail", format!("got {}", args[0])))
+        }
+        fn add(vm: &mut VM, args: &[Value]) -> Result<Value, Error> {
+            args[0].clone().checked_add(args[1].clone(), vm.heap())
+        }
+
+        let mut program = crate::Parser::new("var x = add(1, 2);")
+            .statements()
+            .unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        vm.define_native("add", 2, add);
+        vm.interpret().unwrap();
+        assert_eq!(vm.global("x"), Some(&Value::from(3.0)));
+
+        let mut program = crate::Parser::new("clock(1);").statements().unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        assert!(matches!(
+            runtime_error(vm.interpret()),
+            Error::ArityMismatch {
+                expected: 0,
+                found: 1
+            }
+        ));
+
+        let mut program = crate::Parser::new("fail(\"oops\");").statements().unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        vm.define_native("fail", 1, fail);
+        assert!(matches!(
+            runtime_error(vm.interpret()),
+            Error::NativeError { name, message } if name == "fail" && message == "got oops"
+        ));
+    }
+    {
+        // Collect on every allocation while closures, upvalues, instances and
+        // strings are live only through the stack, frames and globals.
+        let input = "
+            fun makeCounter(prefix) {
+                var count = 0;
+                fun next() {
+                    count = count + 1;
+                    return prefix + \"!\";
+                }
+                return next;
+            }
+            class Node {
+                init(value, next) {
+                    this.value = value;
+                    this.next = next;
+                }
+                sum() {
+                    if (this.next == nil) return this.value;
+                    return this.value + this.next.sum();
+                }
+            }
+            var counter = makeCounter(\"tick\");
+            var list = nil;
+            var word = \"\";
+            for (var i = 1; i < 20; i = i + 1) {
+                list = Node(i, list);
+                word = counter();
+                var cycle = Node(0, nil);
+                cycle.next = cycle;
+            }
+            var total = list.sum();
+        ";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let mut heap = Heap::new();
+        heap.set_stress_mode(true);
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        vm.interpret().unwrap();
+        assert_eq!(vm.global("total"), Some(&Value::from(190.0)));
+        assert_eq!(vm.global("word"), Some(&Value::string(vm.heap(), "tick!")));
+
+        // Dropping the global frees its 19 nodes and the interned name, once
+        // no loaded script holds the name as a constant.
+        let function = crate::compiler::compile(&mut [], vm.heap()).unwrap();
+        vm.load(function);
+        vm.collect_garbage();
+        let live = vm.heap().object_count();
+        let list = vm.heap().find_string("list").unwrap();
+        vm.globals.remove(&list);
+        vm.collect_garbage();
+        assert_eq!(vm.heap().object_count(), live - 20);
+        assert_eq!(vm.heap().find_string("list"), None);
+    }
+    {
+        // Later scripts see the globals of earlier ones, even after an error.
+        let mut program = crate::Parser::new("var a = 1; fun next() { a = a + 1; return a; }")
+            .statements()
+            .unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        vm.interpret().unwrap();
+        for (input, ok) in [("next(); missing;", false), ("var b = next();", true)] {
+            let mut program = crate::Parser::new(input).statements().unwrap();
+            let function = crate::compiler::compile(&mut program, vm.heap()).unwrap();
+            vm.load(function);
+            assert_eq!(vm.interpret().is_ok(), ok);
+        }
+        assert_eq!(vm.global("b"), Some(&Value::from(3.0)));
+        // Once the script has returned, it runs again from its start.
+        vm.interpret().unwrap();
+        assert_eq!(vm.global("b"), Some(&Value::from(4.0)));
+    }
+    {
+        let input = "var a = \"lo\" + \"x\"; var b = \"l\" + \"ox\"; var same = a == b;";
+        let mut program = crate::Parser::new(input).statements().unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        vm.interpret().unwrap();
+        assert_eq!(vm.global("same"), Some(&Value::from(true)));
+        let (Some(Value::String(a)), Some(Value::String(b))) = (vm.global("a"), vm.global("b"))
+        else {
+            panic!("expected strings");
+        };
+        assert!(Gc::ptr_eq(a, b));
+    }
+    {
+        let terms: Vec<String> = (0..300).map(|i| i.to_string()).collect();
+        let input = format!("var sum; sum = {};", terms.join(" + "));
+        let mut program = crate::Parser::new(&input).statements().unwrap();
+        let heap = Heap::new();
+        let mut vm = VM::new(crate::compiler::compile(&mut program, &heap).unwrap(), heap);
+        vm.interpret().unwrap();
+        assert_eq!(vm.global("sum"), Some(&Value::from(44850.0)));
+    }
 }